@@ -1,4 +1,6 @@
-use crate::error::{liq_error, LIQ_OK, LIQ_VALUE_OUT_OF_RANGE};
+use crate::cancel::CancellationToken;
+use crate::error::Error;
+use crate::event::{Event, EventCallback};
 use crate::ffi::MagicTag;
 use crate::ffi::LIQ_ATTR_MAGIC;
 use crate::ffi::LIQ_FREED_MAGIC;
@@ -8,6 +10,7 @@ use crate::pal::PalLen;
 use crate::pal::RGBA;
 use crate::quant::{mse_to_quality, quality_to_mse, QuantizationResult};
 use crate::remap::DitherMapMode;
+use crate::threads::Threads;
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -22,20 +25,32 @@ pub struct Attributes {
     pub(crate) max_histogram_entries: u32,
     min_posterization_output: u8,
     min_posterization_input: u8,
+    pub(crate) histogram_precision: u8,
     pub(crate) last_index_transparent: bool,
+    pub(crate) grayscale: bool,
     pub(crate) use_contrast_maps: bool,
     pub(crate) use_dither_map: DitherMapMode,
+    pub(crate) fixed_colors: Vec<RGBA>,
     speed: u8,
     pub(crate) progress_stage1: u8,
     pub(crate) progress_stage2: u8,
     pub(crate) progress_stage3: u8,
+    pub(crate) cancel: CancellationToken,
+    pub(crate) threads: Threads,
 
     progress_callback: Option<Arc<dyn Fn(f32) -> ControlFlow + Send + Sync>>,
     log_callback: Option<Arc<dyn Fn(&Attributes, &str) + Send + Sync>>,
     log_flush_callback: Option<Arc<dyn Fn(&Attributes) + Send + Sync>>,
+    pub(crate) event_callback: Option<EventCallback>,
 }
 
 impl Attributes {
+    /// Configure all settings at once, and get an error if any of them are invalid
+    #[inline]
+    pub fn builder() -> AttributesBuilder {
+        AttributesBuilder::default()
+    }
+
     /// New handle for library configuration
     ///
     /// See also `new_image()`
@@ -48,46 +63,52 @@ impl Attributes {
             max_mse: None,
             max_colors: 256,
             last_index_transparent: false,
+            grayscale: false,
             kmeans_iteration_limit: 0.,
             max_histogram_entries: 0,
             min_posterization_output: 0,
             min_posterization_input: 0,
+            histogram_precision: 12,
             kmeans_iterations: 0,
             feedback_loop_trials: 0,
             use_contrast_maps: false,
             use_dither_map: DitherMapMode::None,
+            fixed_colors: Vec::new(),
             speed: 0,
             progress_stage1: 0,
             progress_stage2: 0,
             progress_stage3: 0,
+            cancel: CancellationToken::new(),
+            threads: Threads::default(),
             progress_callback: None,
             log_callback: None,
             log_flush_callback: None,
+            event_callback: None,
         };
-        attr.set_speed(4);
+        let _ = attr.set_speed(4); // always in range
         attr
     }
 
     /// It's better to use `set_quality()`
     #[inline]
-    pub fn set_max_colors(&mut self, colors: u32) -> liq_error {
+    pub fn set_max_colors(&mut self, colors: u32) -> Result<(), Error> {
         if !(2..=256).contains(&colors) {
-            return LIQ_VALUE_OUT_OF_RANGE;
+            return Err(Error::out_of_range("max_colors", colors));
         }
         self.max_colors = colors as PalLen;
-        LIQ_OK
+        Ok(())
     }
 
     /// Number of least significant bits to ignore.
     ///
     /// Useful for generating palettes for VGA, 15-bit textures, or other retro platforms.
     #[inline]
-    pub fn set_min_posterization(&mut self, value: u8) -> liq_error {
+    pub fn set_min_posterization(&mut self, value: u8) -> Result<(), Error> {
         if !(0..=4).contains(&value) {
-            return LIQ_VALUE_OUT_OF_RANGE;
+            return Err(Error::out_of_range("min_posterization", value));
         }
         self.min_posterization_output = value;
-        LIQ_OK
+        Ok(())
     }
 
     /// Returns number of bits of precision truncated
@@ -97,18 +118,42 @@ impl Attributes {
         self.min_posterization_output
     }
 
+    /// Bits per channel used to tell colors of 16-bit images apart in the histogram (8-16, default 12).
+    ///
+    /// Higher precision keeps smooth gradients more accurate, but makes the histogram larger and slower.
+    /// If the histogram grows beyond the limit for the current speed, precision is lowered automatically.
+    /// It has no effect on 8-bit images.
+    #[inline]
+    pub fn set_histogram_precision(&mut self, bits: u8) -> Result<(), Error> {
+        if !(8..=16).contains(&bits) {
+            return Err(Error::out_of_range("histogram_precision", bits));
+        }
+        self.histogram_precision = bits;
+        Ok(())
+    }
+
+    /// Returns bits per channel used for 16-bit images
+    #[inline(always)]
+    #[must_use]
+    pub fn histogram_precision(&self) -> u8 {
+        self.histogram_precision
+    }
+
     /// Range 0-100, roughly like JPEG.
     ///
     /// If minimum quality can't be met, quantization will fail.
     ///
     /// Default is min 0, max 100.
-    pub fn set_quality(&mut self, minimum: u8, target: u8) -> liq_error {
-        if !(0..=100).contains(&target) || target < minimum {
-            return LIQ_VALUE_OUT_OF_RANGE;
+    pub fn set_quality(&mut self, minimum: u8, target: u8) -> Result<(), Error> {
+        if !(0..=100).contains(&target) {
+            return Err(Error::out_of_range("target quality", target));
+        }
+        if target < minimum {
+            return Err(Error::out_of_range("minimum quality", minimum));
         }
         self.target_mse = quality_to_mse(target);
         self.max_mse = Some(quality_to_mse(minimum));
-        LIQ_OK
+        Ok(())
     }
 
     /// Reads values set with `set_quality`
@@ -125,9 +170,9 @@ impl Attributes {
     /// Faster speeds generate images of lower quality, but may be useful
     /// for real-time generation of images.
     #[inline]
-    pub fn set_speed(&mut self, value: i32) -> liq_error {
+    pub fn set_speed(&mut self, value: i32) -> Result<(), Error> {
         if !(1..=10).contains(&value) {
-            return LIQ_VALUE_OUT_OF_RANGE;
+            return Err(Error::out_of_range("speed", value));
         }
         let mut iterations = (8 - value).max(0) as u16;
         iterations += iterations * iterations / 2;
@@ -148,7 +193,7 @@ impl Attributes {
         }
         self.progress_stage3 = (50 / (1 + value)) as u8;
         self.progress_stage2 = 100 - self.progress_stage1 - self.progress_stage3;
-        LIQ_OK
+        Ok(())
     }
 
     /// Move transparent color to the last entry in the palette
@@ -159,6 +204,24 @@ impl Attributes {
         self.last_index_transparent = is_last;
     }
 
+    /// Generate a palette of only neutral (gray) colors
+    ///
+    /// Colors of images are reduced to their luma, so the histogram, median cut and K-means
+    /// have only brightness and alpha to work with, and remapping can use a faster search.
+    ///
+    /// Must be set before images and histograms are created.
+    #[inline(always)]
+    pub fn set_grayscale(&mut self, grayscale: bool) {
+        self.grayscale = grayscale;
+    }
+
+    /// Returns whether palettes will have only gray colors
+    #[inline(always)]
+    #[must_use]
+    pub fn grayscale(&self) -> bool {
+        self.grayscale
+    }
+
     /// Return currently set speed/quality trade-off setting
     #[inline(always)]
     #[must_use]
@@ -177,31 +240,31 @@ impl Attributes {
     ///
     /// Use 0.0 for gamma if the image is sRGB (most images are).
     #[inline]
-    pub fn new_image<'pixels>(&self, bitmap: &'pixels [RGBA], width: usize, height: usize, gamma: f64) -> Result<Image<'pixels, 'static>, liq_error> {
+    pub fn new_image<'pixels>(&self, bitmap: &'pixels [RGBA], width: usize, height: usize, gamma: f64) -> Result<Image<'pixels, 'static>, Error> {
         Image::new(self, bitmap, width, height, gamma)
     }
 
     /// Stride is in pixels. Allows defining regions of larger images or images with padding without copying.
     #[inline]
-    pub fn new_image_stride_borrow<'pixels>(&self, bitmap: &'pixels [RGBA], width: usize, height: usize, stride: usize, gamma: f64) -> Result<Image<'pixels, 'static>, liq_error> {
+    pub fn new_image_stride_borrow<'pixels>(&self, bitmap: &'pixels [RGBA], width: usize, height: usize, stride: usize, gamma: f64) -> Result<Image<'pixels, 'static>, Error> {
         Image::new_stride(self, bitmap, width, height, stride, gamma)
     }
 
     /// Like `new_image_stride`, but makes a copy of the pixels
     #[inline]
-    pub fn new_image_stride(&self, bitmap: &[RGBA], width: usize, height: usize, stride: usize, gamma: f64) -> Result<Image<'static, 'static>, liq_error> {
+    pub fn new_image_stride(&self, bitmap: &[RGBA], width: usize, height: usize, stride: usize, gamma: f64) -> Result<Image<'static, 'static>, Error> {
         Image::new_stride_copy(self, bitmap, width, height, stride, gamma)
     }
 
     #[doc(hidden)]
     #[deprecated(note = "use new_image_stride")]
     #[cold]
-    pub fn new_image_stride_copy(&self, bitmap: &[RGBA], width: usize, height: usize, stride: usize, gamma: f64) -> Result<Image<'static, 'static>, liq_error> {
+    pub fn new_image_stride_copy(&self, bitmap: &[RGBA], width: usize, height: usize, stride: usize, gamma: f64) -> Result<Image<'static, 'static>, Error> {
         self.new_image_stride(bitmap, width, height, stride, gamma)
     }
 
     /// Generate palette for the image
-    pub fn quantize(&mut self, image: &mut Image<'_, '_>) -> Result<QuantizationResult, liq_error> {
+    pub fn quantize(&mut self, image: &mut Image<'_, '_>) -> Result<QuantizationResult, Error> {
         let mut hist = Histogram::new(self);
         hist.add_image(self, image)?;
         hist.quantize_internal(self, false)
@@ -229,6 +292,60 @@ impl Attributes {
         self.progress_callback = Some(Arc::new(callback));
     }
 
+    /// Set callback function to be called after each step of quantization, with its numbers and duration.
+    ///
+    /// Unlike the log callback, it doesn't need parsing. Results of quantization inherit the callback, and report remapping too.
+    ///
+    /// To share data with the callback, use `Arc` or `Atomic*` types and `move ||` closures.
+    #[inline]
+    pub fn set_event_callback<F: Fn(&Event) + Send + Sync + 'static>(&mut self, callback: F) {
+        self.event_callback = Some(Arc::new(callback));
+    }
+
+    #[inline]
+    pub(crate) fn emit(&self, event: Event) {
+        if let Some(f) = &self.event_callback {
+            f(&event);
+        }
+    }
+
+    /// Quantization will fail with [`Error::Aborted`] soon after the token is cancelled.
+    ///
+    /// Results of quantization use the same token when remapping, unless they're given another one.
+    #[inline]
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel = token;
+    }
+
+    /// Run K-means and remapping in this pool instead of the global rayon pool.
+    ///
+    /// Results of quantization use the same pool when remapping.
+    #[cfg(feature = "threads")]
+    #[inline]
+    pub fn set_thread_pool(&mut self, pool: Arc<rayon::ThreadPool>) {
+        self.threads = Threads::Pool(pool);
+    }
+
+    /// Use at most this many threads, in a new pool. With 1, everything runs on the caller's thread, without a pool.
+    ///
+    /// Without the `threads` feature everything runs on the caller's thread, and this only checks the value.
+    pub fn set_max_threads(&mut self, threads: usize) -> Result<(), Error> {
+        if threads == 0 {
+            return Err(Error::out_of_range("max threads", 0));
+        }
+        if threads == 1 {
+            self.threads = Threads::Caller;
+            return Ok(());
+        }
+        #[cfg(feature = "threads")]
+        {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build()
+                .map_err(|_| Error::Unsupported("can't start threads"))?;
+            self.set_thread_pool(Arc::new(pool));
+        }
+        Ok(())
+    }
+
     // true == abort
     #[inline]
     pub(crate) fn progress(self: &Attributes, percent: f32) -> bool {
@@ -327,6 +444,182 @@ impl Default for Attributes {
     }
 }
 
+/// Validated configuration for [`Attributes`]. See [`Attributes::builder`].
+///
+/// Settings are checked together in [`AttributesBuilder::build`], instead of one setter at a time.
+/// The K-means, feedback loop and histogram limits are normally derived from the speed,
+/// and if set here, they override what the speed would choose.
+///
+/// With the `serde` feature it can be read from config files. Missing fields keep their defaults.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default, deny_unknown_fields))]
+#[must_use]
+pub struct AttributesBuilder {
+    speed: i32,
+    min_quality: u8,
+    target_quality: u8,
+    max_colors: u32,
+    min_posterization: u8,
+    histogram_precision: u8,
+    last_index_transparent: bool,
+    grayscale: bool,
+    fixed_colors: Vec<RGBA>,
+    dither_map: Option<DitherMapMode>,
+    kmeans_iterations: Option<u16>,
+    feedback_loop_trials: Option<u16>,
+    max_histogram_entries: Option<u32>,
+}
+
+impl Default for AttributesBuilder {
+    fn default() -> Self {
+        Self {
+            speed: 4,
+            min_quality: 0,
+            target_quality: 100,
+            max_colors: 256,
+            min_posterization: 0,
+            histogram_precision: 12,
+            last_index_transparent: false,
+            grayscale: false,
+            fixed_colors: Vec::new(),
+            dither_map: None,
+            kmeans_iterations: None,
+            feedback_loop_trials: None,
+            max_histogram_entries: None,
+        }
+    }
+}
+
+impl AttributesBuilder {
+    /// 1-10. See [`Attributes::set_speed`].
+    #[inline]
+    pub fn speed(mut self, speed: i32) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// 0-100, minimum ≤ target. See [`Attributes::set_quality`].
+    #[inline]
+    pub fn quality(mut self, minimum: u8, target: u8) -> Self {
+        self.min_quality = minimum;
+        self.target_quality = target;
+        self
+    }
+
+    /// 2-256, and not fewer than the fixed colors. See [`Attributes::set_max_colors`].
+    #[inline]
+    pub fn max_colors(mut self, colors: u32) -> Self {
+        self.max_colors = colors;
+        self
+    }
+
+    /// 0-4. See [`Attributes::set_min_posterization`].
+    #[inline]
+    pub fn min_posterization(mut self, bits: u8) -> Self {
+        self.min_posterization = bits;
+        self
+    }
+
+    /// 8-16. See [`Attributes::set_histogram_precision`].
+    #[inline]
+    pub fn histogram_precision(mut self, bits: u8) -> Self {
+        self.histogram_precision = bits;
+        self
+    }
+
+    /// See [`Attributes::set_last_index_transparent`].
+    #[inline]
+    pub fn last_index_transparent(mut self, is_last: bool) -> Self {
+        self.last_index_transparent = is_last;
+        self
+    }
+
+    /// See [`Attributes::set_grayscale`].
+    #[inline]
+    pub fn grayscale(mut self, grayscale: bool) -> Self {
+        self.grayscale = grayscale;
+        self
+    }
+
+    /// Colors reserved in every palette made with these attributes, as if they were added with [`Image::add_fixed_color`].
+    ///
+    /// They're assumed to have the same gamma and color space as the image they're used with.
+    #[inline]
+    pub fn fixed_colors(mut self, colors: &[RGBA]) -> Self {
+        self.fixed_colors = colors.to_vec();
+        self
+    }
+
+    /// Whether remapping looks for edges and noise to decide where to dither.
+    ///
+    /// Speed 6 and below enables it, and 2 and below uses it even for very large images.
+    #[inline]
+    pub fn dither_map(mut self, mode: DitherMapMode) -> Self {
+        self.dither_map = Some(mode);
+        self
+    }
+
+    /// Maximum number of K-means passes refining the final palette (0-1000)
+    #[inline]
+    pub fn kmeans_iterations(mut self, iterations: u16) -> Self {
+        self.kmeans_iterations = Some(iterations);
+        self
+    }
+
+    /// Maximum number of median cut + K-means trials searching for the best palette (0-1000)
+    #[inline]
+    pub fn feedback_loop_trials(mut self, trials: u16) -> Self {
+        self.feedback_loop_trials = Some(trials);
+        self
+    }
+
+    /// Number of unique colors above which the histogram is posterized to make it smaller (at least 256)
+    #[inline]
+    pub fn max_histogram_entries(mut self, entries: u32) -> Self {
+        self.max_histogram_entries = Some(entries);
+        self
+    }
+
+    /// Checks all the settings, and makes [`Attributes`] with them
+    pub fn build(self) -> Result<Attributes, Error> {
+        let mut attr = Attributes::new();
+        attr.set_speed(self.speed)?;
+        attr.set_quality(self.min_quality, self.target_quality)?;
+        attr.set_max_colors(self.max_colors)?;
+        attr.set_min_posterization(self.min_posterization)?;
+        attr.set_histogram_precision(self.histogram_precision)?;
+        if self.fixed_colors.len() > self.max_colors as usize {
+            return Err(Error::out_of_range("number of fixed colors", self.fixed_colors.len() as f64));
+        }
+        if let Some(iterations) = self.kmeans_iterations {
+            if iterations > 1000 {
+                return Err(Error::out_of_range("kmeans_iterations", iterations));
+            }
+            attr.kmeans_iterations = iterations;
+        }
+        if let Some(trials) = self.feedback_loop_trials {
+            if trials > 1000 {
+                return Err(Error::out_of_range("feedback_loop_trials", trials));
+            }
+            attr.feedback_loop_trials = trials;
+        }
+        if let Some(entries) = self.max_histogram_entries {
+            if entries < 256 {
+                return Err(Error::out_of_range("max_histogram_entries", entries));
+            }
+            attr.max_histogram_entries = entries;
+        }
+        if let Some(mode) = self.dither_map {
+            attr.use_dither_map = mode;
+            attr.use_contrast_maps |= mode != DitherMapMode::None;
+        }
+        attr.last_index_transparent = self.last_index_transparent;
+        attr.grayscale = self.grayscale;
+        attr.fixed_colors = self.fixed_colors;
+        Ok(attr)
+    }
+}
+
 /// Result of callback in [`Attributes::set_progress_callback`]
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
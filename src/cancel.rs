@@ -0,0 +1,32 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A flag that stops quantization or remapping from another thread, e.g. when a request times out.
+///
+/// Clones share the same flag. Give one to [`Attributes::set_cancellation_token`](crate::Attributes::set_cancellation_token)
+/// or [`QuantizationResult::set_cancellation_token`](crate::QuantizationResult::set_cancellation_token), and call [`cancel`](Self::cancel) on another.
+/// Work stops soon after, and fails with [`Error::Aborted`](crate::Error::Aborted).
+///
+/// Unlike the progress callback, it's also checked inside parallel loops.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops all work using this token. It can't be undone.
+    #[inline]
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
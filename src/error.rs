@@ -72,3 +72,92 @@ impl liq_error {
         assert!(self.is_ok(), "{}", msg);
     }
 }
+
+/// Error returned by the Rust API. The C API reports these as [`liq_error`] codes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The palette didn't meet the minimum quality set with [`Attributes::set_quality`](crate::Attributes::set_quality)
+    QualityTooLow {
+        /// Quality (0-100) that could be achieved
+        quality: u8,
+        /// The required minimum
+        min_quality: u8,
+    },
+    /// An argument or setting was outside of its allowed range
+    ValueOutOfRange {
+        /// Name of the argument
+        name: &'static str,
+        /// The rejected value
+        value: f64,
+    },
+    OutOfMemory,
+    /// Cancelled by a callback
+    Aborted {
+        /// What was being done when it was cancelled
+        stage: Stage,
+    },
+    BitmapNotAvailable,
+    /// A pixel or output buffer is shorter than the image needs. Sizes are in elements of the buffer, not bytes.
+    BufferTooSmall {
+        expected: usize,
+        actual: usize,
+    },
+    /// The operation can't be done with this image or in this state. The string describes why.
+    Unsupported(&'static str),
+}
+
+/// Part of the quantization process
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum Stage {
+    /// Counting colors of the input image
+    Histogram,
+    /// Choosing the palette
+    Quantization,
+    /// Mapping pixels to the palette
+    Remapping,
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::QualityTooLow { quality, min_quality } => write!(f, "quality {} is below the minimum of {}", quality, min_quality),
+            Self::ValueOutOfRange { name, value } => write!(f, "{} is out of range: {}", name, value),
+            Self::OutOfMemory => f.write_str("out of memory"),
+            Self::Aborted { stage } => write!(f, "aborted during {}", stage),
+            Self::BitmapNotAvailable => f.write_str("bitmap not available"),
+            Self::BufferTooSmall { expected, actual } => write!(f, "buffer too small: needs {}, got {}", expected, actual),
+            Self::Unsupported(what) => write!(f, "unsupported: {}", what),
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            Self::Histogram => "histogram",
+            Self::Quantization => "quantization",
+            Self::Remapping => "remapping",
+        })
+    }
+}
+
+impl Error {
+    #[cold]
+    pub(crate) fn out_of_range(name: &'static str, value: impl Into<f64>) -> Self {
+        Self::ValueOutOfRange { name, value: value.into() }
+    }
+
+    /// Checks that a buffer has at least `expected` elements
+    #[inline]
+    pub(crate) fn check_len(expected: usize, actual: usize) -> Result<(), Self> {
+        if actual < expected {
+            return Err(Self::BufferTooSmall { expected, actual });
+        }
+        Ok(())
+    }
+}
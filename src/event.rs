@@ -0,0 +1,53 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Progress report for [`Attributes::set_event_callback`](crate::Attributes::set_event_callback)
+///
+/// Errors are in the same units as [`QuantizationResult::quantization_error`](crate::QuantizationResult::quantization_error).
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Event {
+    /// Colors of all images added to the histogram have been counted
+    Histogram {
+        /// Unique colors in the histogram
+        colors: usize,
+        /// Bits dropped from each channel to make the histogram smaller
+        posterize_bits: u8,
+        /// Time spent adding images or colors, and preparing the histogram
+        duration: Duration,
+    },
+    /// Median cut made a palette, which is then improved with K-means in a feedback trial
+    MedianCut {
+        colors: usize,
+        duration: Duration,
+    },
+    /// One round of the search for the best palette. There are at most `trials` of them, and `trial` counts from 1.
+    FeedbackTrial {
+        trial: u16,
+        trials: u16,
+        colors: usize,
+        palette_error: f64,
+        /// Median cut and K-means together
+        duration: Duration,
+    },
+    /// One pass of K-means refining the final palette. `iteration` counts from 1 to at most `iterations`.
+    KmeansIteration {
+        iteration: u16,
+        iterations: u16,
+        palette_error: f64,
+        duration: Duration,
+    },
+    /// The image has been remapped without dithering to find areas that need it
+    DitherMap {
+        duration: Duration,
+    },
+    /// The image has been remapped
+    Remap {
+        /// `None` if it wasn't measured (when dithering)
+        remapping_error: Option<f64>,
+        /// Including the dither map, if any
+        duration: Duration,
+    },
+}
+
+pub(crate) type EventCallback = Arc<dyn Fn(&Event) + Send + Sync>;
@@ -58,6 +58,29 @@ macro_rules! bad_object {
     }};
 }
 
+impl From<Error> for liq_error {
+    #[cold]
+    fn from(err: Error) -> Self {
+        match err {
+            Error::QualityTooLow { .. } => Self::LIQ_QUALITY_TOO_LOW,
+            Error::ValueOutOfRange { .. } => Self::LIQ_VALUE_OUT_OF_RANGE,
+            Error::OutOfMemory => Self::LIQ_OUT_OF_MEMORY,
+            Error::Aborted { .. } => Self::LIQ_ABORTED,
+            Error::BitmapNotAvailable => Self::LIQ_BITMAP_NOT_AVAILABLE,
+            Error::BufferTooSmall { .. } => Self::LIQ_BUFFER_TOO_SMALL,
+            Error::Unsupported(_) => Self::LIQ_UNSUPPORTED,
+        }
+    }
+}
+
+#[inline]
+fn liq_error_from(res: Result<(), Error>) -> liq_error {
+    match res {
+        Ok(()) => LIQ_OK,
+        Err(err) => err.into(),
+    }
+}
+
 #[no_mangle]
 #[inline(never)]
 pub extern "C" fn liq_version() -> c_uint {
@@ -144,7 +167,7 @@ pub unsafe extern "C" fn liq_set_log_flush_callback(attr: &mut liq_attr, callbac
 #[inline(never)]
 pub extern "C" fn liq_set_max_colors(attr: &mut liq_attr, colors: c_uint) -> liq_error {
     if bad_object!(attr, LIQ_ATTR_MAGIC) { return LIQ_INVALID_POINTER; }
-    attr.set_max_colors(colors)
+    liq_error_from(attr.set_max_colors(colors))
 }
 
 #[no_mangle]
@@ -158,7 +181,7 @@ pub extern "C" fn liq_get_max_colors(attr: &liq_attr) -> c_uint {
 #[inline(never)]
 pub extern "C" fn liq_set_min_posterization(attr: &mut liq_attr, bits: c_int) -> liq_error {
     if bad_object!(attr, LIQ_ATTR_MAGIC) { return LIQ_INVALID_POINTER; }
-    attr.set_min_posterization(bits as u8)
+    liq_error_from(attr.set_min_posterization(bits as u8))
 }
 
 #[no_mangle]
@@ -171,7 +194,7 @@ pub extern "C" fn liq_get_min_posterization(attr: &liq_attr) -> c_uint {
 #[no_mangle]
 #[inline(never)]
 pub extern "C" fn liq_set_speed(attr: &mut liq_attr, speed: c_int) -> liq_error {
-    attr.set_speed(speed)
+    liq_error_from(attr.set_speed(speed))
 }
 
 #[no_mangle]
@@ -185,7 +208,7 @@ pub extern "C" fn liq_get_speed(attr: &liq_attr) -> c_uint {
 #[inline(never)]
 pub extern "C" fn liq_set_quality(attr: &mut liq_attr, minimum: c_uint, target: c_uint) -> liq_error {
     if bad_object!(attr, LIQ_ATTR_MAGIC) { return LIQ_INVALID_POINTER; }
-    attr.set_quality(minimum as u8, target as u8)
+    liq_error_from(attr.set_quality(minimum as u8, target as u8))
 }
 
 #[no_mangle]
@@ -226,7 +249,7 @@ pub unsafe extern "C" fn liq_write_remapped_image(result: &mut liq_result, input
     let buffer_bytes = std::slice::from_raw_parts_mut(buffer_bytes, required_size);
 
     let rows = RowBitmapMut::new_contiguous(buffer_bytes, input_image.width());
-    result.write_remapped_image_rows_internal(input_image, rows).err().unwrap_or(LIQ_OK)
+    liq_error_from(result.write_remapped_image_rows_internal(input_image, rows))
 }
 
 #[no_mangle]
@@ -240,21 +263,21 @@ pub unsafe extern "C" fn liq_write_remapped_image_rows(result: &mut liq_result,
     let rows = std::slice::from_raw_parts_mut(row_pointers, input_image.height());
     let rows = RowBitmapMut::new(rows, input_image.width());
 
-    result.write_remapped_image_rows_internal(input_image, rows).err().unwrap_or(LIQ_OK)
+    liq_error_from(result.write_remapped_image_rows_internal(input_image, rows))
 }
 
 #[no_mangle]
 #[inline(never)]
 pub extern "C" fn liq_image_add_fixed_color(img: &mut Image, color: liq_color) -> liq_error {
     if bad_object!(img, LIQ_IMAGE_MAGIC) { return LIQ_INVALID_POINTER; }
-    img.add_fixed_color(color)
+    liq_error_from(img.add_fixed_color(color))
 }
 
 #[no_mangle]
 #[inline(never)]
 pub extern "C" fn liq_histogram_add_fixed_color(hist: &mut Histogram, color: liq_color, gamma: f64) -> liq_error {
     if bad_object!(hist, LIQ_HISTOGRAM_MAGIC) { return LIQ_INVALID_POINTER; }
-    hist.add_fixed_color(color, gamma)
+    liq_error_from(hist.add_fixed_color(color, gamma))
 }
 
 #[no_mangle]
@@ -281,7 +304,7 @@ pub extern "C" fn liq_image_set_background<'pixels, 'rows>(img: &mut Image<'pixe
     if bad_object!(img, LIQ_IMAGE_MAGIC) ||
        bad_object!(background, LIQ_IMAGE_MAGIC) { return LIQ_INVALID_POINTER; }
 
-    img.set_background(*background).err().unwrap_or(LIQ_OK)
+    liq_error_from(img.set_background(*background))
 }
 
 #[no_mangle]
@@ -315,7 +338,7 @@ pub unsafe extern "C" fn liq_image_set_importance_map(img: &mut Image, importanc
 #[inline(never)]
 pub unsafe extern "C" fn liq_image_set_memory_ownership(img: &mut Image, ownership_flags: liq_ownership) -> liq_error {
     if bad_object!(img, LIQ_IMAGE_MAGIC) { return LIQ_INVALID_POINTER; }
-    img.px.set_memory_ownership(ownership_flags).err().unwrap_or(LIQ_OK)
+    liq_error_from(img.px.set_memory_ownership(ownership_flags))
 }
 
 #[no_mangle]
@@ -361,14 +384,14 @@ pub extern "C" fn liq_result_destroy(_res: Option<Box<liq_result>>) {}
 #[inline(never)]
 pub extern "C" fn liq_set_output_gamma(result: &mut liq_result, gamma: f64) -> liq_error {
     if bad_object!(result, LIQ_RESULT_MAGIC) { return LIQ_INVALID_POINTER; }
-    result.set_output_gamma(gamma)
+    liq_error_from(result.set_output_gamma(gamma))
 }
 
 #[no_mangle]
 #[inline(never)]
 pub extern "C" fn liq_set_dithering_level(result: &mut liq_result, dither_level: f32) -> liq_error {
     if bad_object!(result, LIQ_RESULT_MAGIC) { return LIQ_INVALID_POINTER; }
-    result.set_dithering_level(dither_level)
+    liq_error_from(result.set_dithering_level(dither_level))
 }
 
 #[no_mangle]
@@ -413,7 +436,7 @@ pub fn liq_image_quantize(img: &mut Image, attr: &liq_attr, write_only_output: &
        bad_object!(img, LIQ_IMAGE_MAGIC) { return LIQ_INVALID_POINTER; }
 
     let mut hist = Histogram::new(attr);
-    if let Err(err) = hist.add_image(attr, img) { return err; }
+    if let Err(err) = hist.add_image(attr, img) { return err.into(); }
     store_boxed_result(hist.quantize_internal(attr, false), write_only_output)
 }
 
@@ -427,10 +450,10 @@ pub fn liq_histogram_quantize(hist: &mut Histogram, attr: &liq_attr, write_only_
 }
 
 #[inline]
-fn store_boxed_result<T>(res: Result<T, liq_error>, out: &mut MaybeUninit<Option<Box<T>>>) -> liq_error {
+fn store_boxed_result<T>(res: Result<T, Error>, out: &mut MaybeUninit<Option<Box<T>>>) -> liq_error {
     match res {
         Ok(res) => { out.write(Some(Box::new(res))); LIQ_OK },
-        Err(err) => { out.write(None); err },
+        Err(err) => { out.write(None); err.into() },
     }
 }
 
@@ -456,7 +479,7 @@ pub(crate) fn check_image_size(attr: &liq_attr, width: u32, height: u32) -> bool
 pub unsafe extern "C" fn liq_image_create_custom(attr: &liq_attr, row_callback: liq_image_get_rgba_row_callback, user_info: AnySyncSendPtr, width: c_uint, height: c_uint, gamma: f64)
  -> Option<Box<Image<'static, 'static>>> {
     let db: Box<dyn Fn(&mut [MaybeUninit<RGBA>], usize) + Send + Sync> = Box::new(move |row, y| row_callback(row.as_mut_ptr(), y as _, row.len() as _, user_info.0));
-    liq_image::new_internal(attr, PixelsSource::Callback(db), width, height, gamma).ok().map(Box::new)
+    liq_image::new_internal(attr, PixelsSource::Callback(db), width, height, gamma.into()).ok().map(Box::new)
 }
 
 #[no_mangle]
@@ -470,7 +493,7 @@ pub unsafe extern "C" fn liq_image_create_rgba_rows<'rows>(attr: &liq_attr, rows
     if rows_slice.iter().any(|r| r.is_null()) {
         return None;
     }
-    liq_image::new_internal(attr, PixelsSource::Pixels { rows, pixels: None }, width, height, gamma).ok().map(Box::new)
+    liq_image::new_internal(attr, PixelsSource::Pixels { rows, pixels: None, order: ComponentOrder::RGBA }, width, height, gamma.into()).ok().map(Box::new)
 }
 
 #[no_mangle]
@@ -480,7 +503,7 @@ pub unsafe extern "C" fn liq_image_create_rgba(attr: &liq_attr, bitmap: *const l
     if !check_image_size(attr, width, height) { return None; }
 
     let rows = SeaCow::boxed((0..height as usize).map(move |i| bitmap.add(width as usize * i)).collect());
-    liq_image::new_internal(attr, PixelsSource::Pixels { rows, pixels: None }, width, height, gamma).ok().map(Box::new)
+    liq_image::new_internal(attr, PixelsSource::Pixels { rows, pixels: None, order: ComponentOrder::RGBA }, width, height, gamma.into()).ok().map(Box::new)
 }
 
 #[no_mangle]
@@ -497,7 +520,7 @@ pub unsafe extern "C" fn liq_histogram_add_colors(input_hist: &mut Histogram, at
 
     let entries = std::slice::from_raw_parts(entries, num_entries as usize);
 
-    input_hist.add_colors(entries, gamma).err().unwrap_or(LIQ_OK)
+    liq_error_from(input_hist.add_colors(entries, gamma))
 }
 
 #[no_mangle]
@@ -507,7 +530,7 @@ pub extern "C" fn liq_histogram_add_image(input_hist: &mut Histogram, attr: &liq
        bad_object!(input_hist, LIQ_HISTOGRAM_MAGIC) ||
        bad_object!(input_image, LIQ_IMAGE_MAGIC) { return LIQ_INVALID_POINTER; }
 
-    input_hist.add_image(attr, input_image).err().unwrap_or(LIQ_OK)
+    liq_error_from(input_hist.add_image(attr, input_image))
 }
 
 #[no_mangle]
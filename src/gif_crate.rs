@@ -0,0 +1,187 @@
+//! Writing quantized images as GIF files, using the [`gif`](https://lib.rs/gif) crate.
+//!
+//! Requires the `gif` cargo feature.
+
+use crate::attr::Attributes;
+use crate::error::*;
+use crate::image::Image;
+use crate::pal::RGBA;
+use crate::quant::QuantizationResult;
+use gif::{DisposalMethod, EncodingError, Encoder, Frame, Repeat};
+use std::convert::TryFrom;
+use std::io;
+
+/// What happens to a frame of an animation before the next one is drawn
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Disposal {
+    /// The next frame is drawn over this one
+    Keep,
+    /// The frame's area is cleared to transparent
+    Background,
+    /// The frame's area is restored to what was there before it
+    Previous,
+}
+
+impl Image<'static, 'static> {
+    /// Copies the pixels, making them either fully opaque or fully transparent (at alpha 128), since that's all GIF supports.
+    ///
+    /// If there are any transparent pixels, a transparent color is reserved in the palette.
+    pub fn new_gif_frame(attr: &Attributes, pixels: &[RGBA], width: usize, height: usize) -> Result<Self, Error> {
+        let len = width.checked_mul(height).ok_or(Error::out_of_range("image size", width as f64 * height as f64))?;
+        Error::check_len(len, pixels.len())?;
+        let pixels = &pixels[..len];
+        let mut has_transparent = false;
+        let binary: Vec<RGBA> = pixels.iter().map(|&px| if px.a < 128 {
+            has_transparent = true;
+            RGBA::new(0, 0, 0, 0)
+        } else {
+            RGBA { a: 255, ..px }
+        }).collect();
+        let mut img = Image::new_stride_copy(attr, &binary, width, height, width, 0.)?;
+        if has_transparent {
+            img.add_fixed_color(RGBA::new(0, 0, 0, 0))?;
+        }
+        Ok(img)
+    }
+}
+
+/// Writes frames of an animated GIF. Each frame has its own palette.
+pub struct GifWriter<W: io::Write> {
+    encoder: Encoder<W>,
+    width: usize,
+    height: usize,
+}
+
+impl<W: io::Write> GifWriter<W> {
+    /// The animation loops forever. All frames must have the given size.
+    pub fn new(writer: W, width: usize, height: usize) -> io::Result<Self> {
+        let (w, h) = gif_size(width, height)?;
+        let mut encoder = Encoder::new(writer, w, h, &[]).map_err(gif_to_io)?;
+        encoder.set_repeat(Repeat::Infinite).map_err(gif_to_io)?;
+        Ok(Self { encoder, width, height })
+    }
+
+    /// Remap the image and append it as a frame. Delay is in 1/100ths of a second.
+    ///
+    /// Quantize images made with [`Image::new_gif_frame`], so that there's only one transparent color.
+    pub fn add_frame(&mut self, result: &mut QuantizationResult, image: &mut Image<'_, '_>, delay: u16, disposal: Disposal) -> io::Result<()> {
+        if image.width() != self.width {
+            return Err(to_io(Error::out_of_range("frame width", image.width() as f64)));
+        }
+        if image.height() != self.height {
+            return Err(to_io(Error::out_of_range("frame height", image.height() as f64)));
+        }
+        let mut frame = gif_frame(result, image)?;
+        frame.delay = delay;
+        frame.dispose = match disposal {
+            Disposal::Keep => DisposalMethod::Keep,
+            Disposal::Background => DisposalMethod::Background,
+            Disposal::Previous => DisposalMethod::Previous,
+        };
+        self.encoder.write_frame(&frame).map_err(gif_to_io)
+    }
+
+    /// Writes the trailer, and returns the writer
+    pub fn finish(self) -> io::Result<W> {
+        self.encoder.into_inner()
+    }
+}
+
+impl QuantizationResult {
+    /// Remap the image and write it as a single-frame GIF file.
+    ///
+    /// Quantize images made with [`Image::new_gif_frame`], so that there's only one transparent color.
+    pub fn write_gif(&mut self, image: &mut Image<'_, '_>, writer: impl io::Write) -> io::Result<()> {
+        let (w, h) = gif_size(image.width(), image.height())?;
+        let frame = gif_frame(self, image)?;
+        let mut encoder = Encoder::new(writer, w, h, &[]).map_err(gif_to_io)?;
+        encoder.write_frame(&frame).map_err(gif_to_io)?;
+        encoder.into_inner()?;
+        Ok(())
+    }
+}
+
+/// Uses the first mostly-transparent palette entry as the transparent index, and merges others into it
+fn gif_frame(result: &mut QuantizationResult, image: &mut Image<'_, '_>) -> io::Result<Frame<'static>> {
+    let (palette, mut indices) = result.remapped(image).map_err(to_io)?;
+    let transparent = palette.iter().position(|c| c.a < 128);
+    if let Some(transparent) = transparent {
+        let transparent = transparent as u8;
+        for idx in &mut indices {
+            if palette[*idx as usize].a < 128 {
+                *idx = transparent;
+            }
+        }
+    }
+    // the gif crate pads the palette to a power of two
+    let palette: Vec<u8> = palette.iter().flat_map(|c| [c.r, c.g, c.b]).collect();
+    Ok(Frame {
+        width: image.width() as u16,
+        height: image.height() as u16,
+        buffer: indices.into(),
+        palette: Some(palette),
+        transparent: transparent.map(|t| t as u8),
+        ..Frame::default()
+    })
+}
+
+/// GIF dimensions are 16-bit
+fn gif_size(width: usize, height: usize) -> io::Result<(u16, u16)> {
+    let w = u16::try_from(width).map_err(|_| to_io(Error::out_of_range("width", width as f64)))?;
+    let h = u16::try_from(height).map_err(|_| to_io(Error::out_of_range("height", height as f64)))?;
+    Ok((w, h))
+}
+
+fn to_io(err: Error) -> io::Error {
+    io::Error::other(err)
+}
+
+fn gif_to_io(err: EncodingError) -> io::Error {
+    match err {
+        EncodingError::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+#[test]
+fn gif_animation() {
+    let mut liq = Attributes::new();
+    let frames = [
+        [RGBA::new(0, 0, 0, 0), RGBA::new(255, 0, 0, 100), RGBA::new(0, 255, 0, 200), RGBA::new(0, 0, 255, 255)],
+        [RGBA::new(9, 9, 9, 255), RGBA::new(99, 0, 0, 255), RGBA::new(0, 99, 0, 255), RGBA::new(0, 0, 99, 255)],
+    ];
+
+    let mut writer = GifWriter::new(Vec::new(), 2, 2).unwrap();
+    for (i, pixels) in frames.iter().enumerate() {
+        let mut img = Image::new_gif_frame(&liq, pixels, 2, 2).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        writer.add_frame(&mut res, &mut img, 10 * (i as u16 + 1), if i == 0 { Disposal::Background } else { Disposal::Keep }).unwrap();
+    }
+    let file = writer.finish().unwrap();
+
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(&file[..]).unwrap();
+    for (i, pixels) in frames.iter().enumerate() {
+        let frame = decoder.read_next_frame().unwrap().unwrap();
+        assert_eq!(10 * (i as u16 + 1), frame.delay);
+        assert_eq!(if i == 0 { DisposalMethod::Background } else { DisposalMethod::Keep }, frame.dispose);
+        let palette = frame.palette.as_deref().unwrap();
+        for (px, &idx) in pixels.iter().zip(frame.buffer.iter()) {
+            if px.a < 128 {
+                assert_eq!(frame.transparent, Some(idx));
+            } else {
+                assert_ne!(frame.transparent, Some(idx));
+                assert_eq!(&[px.r, px.g, px.b], &palette[idx as usize * 3..idx as usize * 3 + 3]);
+            }
+        }
+        assert_eq!(i == 1, frame.transparent.is_none());
+    }
+    assert!(decoder.read_next_frame().unwrap().is_none());
+
+    let mut img = Image::new_gif_frame(&liq, &frames[0], 2, 2).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let mut file = Vec::new();
+    res.write_gif(&mut img, &mut file).unwrap();
+    assert!(file.starts_with(b"GIF89a"));
+}
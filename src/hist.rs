@@ -1,13 +1,16 @@
+use crate::cancel::CancellationToken;
 use crate::error::*;
+use crate::event::Event;
 use crate::ffi::MagicTag;
 use crate::ffi::{LIQ_FREED_MAGIC, LIQ_HISTOGRAM_MAGIC};
 use crate::image::Image;
 use crate::pal::PalIndex;
 use crate::pal::ARGBF;
-use crate::pal::{f_pixel, gamma_lut, RGBA};
+use crate::pal::{f_pixel, gamma_lut, gamma_lut16, gray, GamutMatrix, TransferFunction, RGBA, RGBA16};
 use crate::quant::QuantizationResult;
 use crate::rows::temp_buf;
 use crate::rows::DynamicRows;
+use crate::stats::QuantizationStats;
 use crate::Attributes;
 use rgb::ComponentSlice;
 use std::collections::{HashMap, HashSet};
@@ -15,6 +18,7 @@ use std::convert::TryInto;
 use std::fmt;
 use std::hash::Hash;
 use std::os::raw::c_uint;
+use std::time::{Duration, Instant};
 
 /// Number of pixels in a given color
 ///
@@ -29,16 +33,27 @@ pub struct HistogramEntry {
 /// Generate one shared palette for multiple images.
 pub struct Histogram {
     pub(crate) magic_header: MagicTag,
-    gamma: Option<f64>,
+    transfer: Option<TransferFunction>,
+    /// colors of images are converted to the output color space when the histogram is finalized
+    gamut: Option<Box<GamutMatrix>>,
     fixed_colors: FixedColorsSet,
 
     /// maps RGBA as u32 to (boosted) count
     hashmap: HashMap<u32, (u32, RGBA), RgbaHasher>,
+    /// same for 16-bit images, with RGBA16 truncated to `precision16` bits as u64
+    hashmap16: HashMap<u64, (u32, RGBA16), RgbaHasher>,
     /// how many pixels were counted
     total_area: usize,
 
     posterize_bits: u8,
+    precision16: u8,
     max_histogram_entries: u32,
+    /// colors added directly are reduced to luma
+    grayscale: bool,
+    /// time spent adding images and colors
+    build_time: Duration,
+    /// entries merged by posterization to fit `max_histogram_entries`
+    posterized_entries: usize,
 }
 
 pub(crate) type FixedColorsSet = HashSet<HashColor, RgbaHasher>;
@@ -87,35 +102,43 @@ impl Histogram {
     pub fn new(attr: &Attributes) -> Self {
         Self {
             posterize_bits: attr.posterize_bits(),
+            precision16: attr.histogram_precision,
             max_histogram_entries: attr.max_histogram_entries,
+            grayscale: attr.grayscale,
             fixed_colors: HashSet::with_hasher(RgbaHasher(0)),
             hashmap: HashMap::with_hasher(RgbaHasher(0)),
+            hashmap16: HashMap::with_hasher(RgbaHasher(0)),
             magic_header: LIQ_HISTOGRAM_MAGIC,
-            gamma: None,
+            transfer: None,
+            gamut: None,
             total_area: 0,
+            build_time: Duration::ZERO,
+            posterized_entries: 0,
         }
     }
 
     /// "Learns" colors from the image, which will be later used to generate the palette.
     ///
     /// Fixed colors added to the image are also added to the histogram. If the total number of fixed colors exceeds 256,
-    /// this function will fail with `LIQ_BUFFER_TOO_SMALL`.
+    /// this function will fail with `Error::Unsupported`.
     #[inline(never)]
-    pub fn add_image(&mut self, attr: &Attributes, image: &mut Image) -> Result<(), liq_error> {
+    pub fn add_image(&mut self, attr: &Attributes, image: &mut Image) -> Result<(), Error> {
+        let start = Instant::now();
         let width = image.width();
         let height = image.height();
         if image.importance_map.is_none() && attr.use_contrast_maps {
             image.contrast_maps()?;
         }
 
-        self.gamma = Some(image.gamma());
+        self.transfer = Some(image.transfer());
+        self.gamut = image.px.gamut.map(Box::new);
 
         for c in image.fixed_colors.iter().copied() {
             self.fixed_colors.insert(HashColor(c));
         }
 
         if attr.progress(attr.progress_stage1 as f32 * 0.40) {
-            return Err(LIQ_ABORTED); // bow can free the RGBA source if copy has been made in f_pixels
+            return Err(Error::Aborted { stage: Stage::Histogram }); // bow can free the RGBA source if copy has been made in f_pixels
         }
 
         let posterize_bits = attr.posterize_bits();
@@ -125,8 +148,9 @@ impl Histogram {
 
         let importance_map = image.importance_map.as_ref().map(|m| m.as_slice());
 
-        self.add_pixel_rows(&mut image.px, importance_map, posterize_bits)?;
+        self.add_pixel_rows(&mut image.px, importance_map, posterize_bits, &attr.cancel)?;
         image.free_histogram_inputs();
+        self.build_time += start.elapsed();
 
         Ok(())
     }
@@ -135,36 +159,37 @@ impl Histogram {
     ///
     /// This function is only useful if you already have a histogram of the image from another source.
     #[inline(never)]
-    pub fn add_colors(&mut self, entries: &[HistogramEntry], gamma: f64) -> Result<(), liq_error> {
+    pub fn add_colors(&mut self, entries: &[HistogramEntry], gamma: impl Into<TransferFunction>) -> Result<(), Error> {
         if entries.is_empty() || entries.len() > 1 << 24 {
-            return Err(LIQ_VALUE_OUT_OF_RANGE);
+            return Err(Error::out_of_range("number of entries", entries.len() as f64));
         }
 
-        if !(0. ..1.).contains(&gamma) {
-            return Err(LIQ_VALUE_OUT_OF_RANGE);
-        }
-
-        self.gamma = Some(if gamma > 0. { gamma } else { 0.45455 });
+        let start = Instant::now();
+        self.transfer = Some(gamma.into().normalized()?);
         self.reserve(entries.len());
 
         self.total_area += entries.len();
         for e in entries {
-            self.add_color(e.color, e.count.try_into().unwrap_or(u16::MAX));
+            let color = if self.grayscale { gray(e.color) } else { e.color };
+            self.add_color(color, e.count.try_into().unwrap_or(u16::MAX).into());
         }
+        self.build_time += start.elapsed();
 
         Ok(())
     }
 
     /// Add a color guaranteed to be in the final palette
-    pub fn add_fixed_color(&mut self, color: RGBA, gamma: f64) -> liq_error {
-        let lut = gamma_lut(if gamma > 0. { gamma } else { 0.45455 });
+    pub fn add_fixed_color(&mut self, color: RGBA, gamma: impl Into<TransferFunction>) -> Result<(), Error> {
+        let transfer = gamma.into().normalized()?;
+        let lut = gamma_lut(transfer);
+        let color = if self.grayscale { gray(color) } else { color };
         let px = f_pixel::from_rgba(&lut, RGBA{r: color.r, g: color.g, b: color.b, a: color.a,});
 
         if self.fixed_colors.len() > 255 {
-            return LIQ_UNSUPPORTED;
+            return Err(Error::Unsupported("more than 256 fixed colors"));
         }
         self.fixed_colors.insert(HashColor(px));
-        LIQ_OK
+        Ok(())
     }
 
     /// Generate palette for all images/colors added to the histogram.
@@ -172,41 +197,86 @@ impl Histogram {
     /// Palette generated using this function won't be improved during remapping.
     /// If you're generating palette for only one image, it's better not to use the `Histogram`.
     #[inline]
-    pub fn quantize(&mut self, attr: &Attributes) -> Result<QuantizationResult, liq_error> {
+    pub fn quantize(&mut self, attr: &Attributes) -> Result<QuantizationResult, Error> {
         self.quantize_internal(attr, true)
     }
 
     #[inline(never)]
-    pub(crate) fn quantize_internal(&mut self, attr: &Attributes, freeze_result_colors: bool) -> Result<QuantizationResult, liq_error> {
-        if self.hashmap.is_empty() && self.fixed_colors.is_empty() {
-            return Err(LIQ_UNSUPPORTED);
+    pub(crate) fn quantize_internal(&mut self, attr: &Attributes, freeze_result_colors: bool) -> Result<QuantizationResult, Error> {
+        let mut fixed_colors = self.fixed_colors.clone();
+        if !attr.fixed_colors.is_empty() {
+            let lut = gamma_lut(self.transfer.unwrap_or(TransferFunction::Gamma(0.45455)));
+            for &color in &attr.fixed_colors {
+                let px = f_pixel::from_rgba(&lut, if self.grayscale { gray(color) } else { color });
+                fixed_colors.insert(HashColor(match self.gamut.as_deref() {
+                    Some(m) => px.convert_gamut(m),
+                    None => px,
+                }));
+            }
+            if fixed_colors.len() > 256 {
+                return Err(Error::Unsupported("more than 256 fixed colors"));
+            }
         }
 
-        if attr.progress(0.) { return Err(LIQ_ABORTED); }
+        if self.len() == 0 && fixed_colors.is_empty() {
+            return Err(Error::Unsupported("histogram is empty"));
+        }
+
+        if attr.progress(0.) || attr.cancel.is_cancelled() { return Err(Error::Aborted { stage: Stage::Histogram }); }
         if attr.progress(attr.progress_stage1 as f32 * 0.89) {
-            return Err(LIQ_ABORTED);
+            return Err(Error::Aborted { stage: Stage::Histogram });
         }
 
-        let gamma = self.gamma.unwrap_or(0.45455);
-        let (_, target_mse, _) = attr.target_mse(self.hashmap.len());
-        let hist = self.finalize_builder(gamma, target_mse);
+        let transfer = self.transfer.unwrap_or(TransferFunction::Gamma(0.45455));
+        let (_, target_mse, _) = attr.target_mse(self.len());
+        let start = Instant::now();
+        let hist = self.finalize_builder(transfer, target_mse);
+
+        let stats = QuantizationStats {
+            histogram_time: self.build_time + start.elapsed(),
+            histogram_entries_before_posterization: self.len() + self.posterized_entries,
+            histogram_entries: hist.items.len(),
+            posterize_bits: self.posterize_bits,
+            ..QuantizationStats::default()
+        };
 
         attr.verbose_print(format!("  made histogram...{} colors found", hist.items.len()));
+        attr.emit(Event::Histogram {
+            colors: stats.histogram_entries,
+            posterize_bits: stats.posterize_bits,
+            duration: stats.histogram_time,
+        });
 
-        QuantizationResult::new(attr, hist, freeze_result_colors, &self.fixed_colors, gamma)
+        QuantizationResult::new(attr, hist, freeze_result_colors, &fixed_colors, transfer, stats)
     }
 
     #[inline(always)]
-    fn add_color(&mut self, rgba: RGBA, boost: u16) {
+    fn add_color(&mut self, rgba: RGBA, boost: u32) {
         let px_int = if rgba.a != 0 {
             self.posterize_mask() & unsafe { RGBAInt { rgba }.int }
         } else { 0 };
 
         self.hashmap.entry(px_int)
+            .and_modify(move |e| e.0 = e.0.saturating_add(boost))
+            .or_insert((boost, rgba));
+    }
+
+    #[inline(always)]
+    fn add_color16(&mut self, rgba: RGBA16, boost: u16) {
+        let px_int = if rgba.a != 0 {
+            self.posterize_mask16() & (u64::from(rgba.r) << 48 | u64::from(rgba.g) << 32 | u64::from(rgba.b) << 16 | u64::from(rgba.a))
+        } else { 0 };
+
+        self.hashmap16.entry(px_int)
             .and_modify(move |e| e.0 += boost as u32)
             .or_insert((boost as u32, rgba));
     }
 
+    #[inline]
+    fn len(&self) -> usize {
+        self.hashmap.len() + self.hashmap16.len()
+    }
+
     fn reserve(&mut self, entries: usize) {
         let new_entries = entries.saturating_sub(self.hashmap.len() / 3); // assume some will be dupes, if called multiple times
         self.hashmap.reserve(new_entries);
@@ -218,6 +288,36 @@ impl Histogram {
         u32::from_ne_bytes([channel_mask, channel_mask, channel_mask, channel_mask])
     }
 
+    #[inline(always)]
+    fn posterize_mask16(&self) -> u64 {
+        let shift = (16 - self.precision16 + self.posterize_bits).min(15);
+        let channel_mask = (0xFFFF << shift) & 0xFFFF;
+        channel_mask << 48 | channel_mask << 32 | channel_mask << 16 | channel_mask
+    }
+
+    /// Merges 16-bit colors into fewer, less precise buckets
+    fn reduce_precision16(&mut self, precision: u8) {
+        if self.precision16 <= precision {
+            return;
+        }
+        self.precision16 = precision;
+        self.rehash16();
+    }
+
+    fn rehash16(&mut self) {
+        if self.hashmap16.is_empty() {
+            return;
+        }
+        let new_posterize_mask = self.posterize_mask16();
+        let new_size = self.hashmap16.len() / 3;
+        let old_hashmap = std::mem::replace(&mut self.hashmap16, HashMap::with_capacity_and_hasher(new_size, RgbaHasher(0)));
+        for (k, (count, color)) in old_hashmap {
+            self.hashmap16.entry(k & new_posterize_mask)
+                .and_modify(move |e| e.0 += count)
+                .or_insert((count, color));
+        }
+    }
+
     /// optionallys et
     fn init_posterize_bits(&mut self, posterize_bits: u8) {
         if self.posterize_bits >= posterize_bits {
@@ -231,9 +331,10 @@ impl Histogram {
         self.hashmap.extend(old_hashmap.into_iter().map(move |(k, v)| {
             (k & new_posterize_mask, v)
         }));
+        self.rehash16();
     }
 
-    pub(crate) fn add_pixel_rows(&mut self, image: &mut DynamicRows<'_, '_>, importance_map: Option<&[u8]>, posterize_bits: u8) -> Result<(), liq_error> {
+    pub(crate) fn add_pixel_rows(&mut self, image: &mut DynamicRows<'_, '_>, importance_map: Option<&[u8]>, posterize_bits: u8, cancel: &CancellationToken) -> Result<(), Error> {
         let width = image.width as usize;
         let height = image.height as usize;
         self.total_area += width * height;
@@ -241,48 +342,91 @@ impl Histogram {
         let mut importance_map = importance_map.unwrap_or(&[]).chunks_exact(width).fuse();
         let image_iter = image.rgba_rows_iter()?;
 
-        let mut temp_row = temp_buf(width);
+        let mut temp_row = temp_buf(image.temp_row_width());
+        let mut temp_row16 = temp_buf(if image.is_high_precision() { image.temp_row_width() } else { 0 });
+        // indexed images are counted per palette entry, and don't need hashing
+        let mut index_counts = [0u64; 256];
+        let mut palette = None;
         for row in 0..height {
-            let pixels_row = &image_iter.row_rgba(&mut temp_row, row)[..width];
+            if cancel.is_cancelled() {
+                return Err(Error::Aborted { stage: Stage::Histogram });
+            }
             let importance_map = importance_map.next().map(move |m| &m[..width]);
+            if let Some((pal, indices)) = image.row_indexed(row) {
+                palette = Some(pal);
+                for (col, &idx) in indices.iter().enumerate() {
+                    index_counts[idx as usize] += importance_map.map(move |map| map[col]).unwrap_or(255) as u64;
+                }
+                continue;
+            }
+            if let Some(pixels_row) = image_iter.row_rgba16(&mut temp_row16, row) {
+                for (col, px) in pixels_row[..width].iter().copied().enumerate() {
+                    self.add_color16(px, importance_map.map(move |map| map[col]).unwrap_or(255) as u16);
+                }
+                continue;
+            }
+            let pixels_row = &image_iter.row_rgba(&mut temp_row, row)[..width];
             for (col, px) in pixels_row.iter().copied().enumerate() {
-                self.add_color(px, importance_map.map(move |map| map[col]).unwrap_or(255) as u16);
+                self.add_color(px, importance_map.map(move |map| map[col]).unwrap_or(255) as u32);
+            }
+        }
+        if let Some(palette) = palette {
+            for (&px, &count) in palette.iter().zip(&index_counts) {
+                if count > 0 {
+                    self.add_color(if image.grayscale { gray(px) } else { px }, count.min(u32::MAX.into()) as u32);
+                }
             }
         }
         self.init_posterize_bits(posterize_bits);
 
+        let entries_before = self.len();
         if self.hashmap.len() > self.max_histogram_entries as usize && self.posterize_bits < 3 {
             self.init_posterize_bits(self.posterize_bits + 1);
         }
+        while self.hashmap16.len() > self.max_histogram_entries as usize && self.precision16 > 8 {
+            self.reduce_precision16(self.precision16 - 1);
+        }
+        self.posterized_entries += entries_before - self.len();
         Ok(())
     }
 
-    pub(crate) fn finalize_builder(&mut self, gamma: f64, target_mse: f64) -> HistogramInternal {
-        debug_assert!(gamma > 0.);
+    pub(crate) fn finalize_builder(&mut self, transfer: TransferFunction, target_mse: f64) -> HistogramInternal {
 
         let mut counts = [0; LIQ_MAXCLUSTER];
-        let mut temp = Vec::with_capacity(self.hashmap.len());
+        let mut temp = Vec::with_capacity(self.len());
         // Limit perceptual weight to 1/10th of the image surface area to prevent
         // a single color from dominating all others.
         let max_perceptual_weight = 0.1 * self.total_area as f32;
 
         let max_fixed_color_difference = (target_mse / 2.).max(2. / 256. / 256.) as f32;
 
-        let lut = gamma_lut(gamma);
-
-        let total_perceptual_weight = self.hashmap.values().map(|&(boost, color)| {
+        let lut = gamma_lut(transfer);
+        let lut16 = if !self.hashmap16.is_empty() { gamma_lut16(transfer) } else { Box::default() };
+
+        let colors = self.hashmap.values().map(|&(boost, color)| {
+            let cluster_index = ((color.r >> 7) << 3) | ((color.g >> 7) << 2) | ((color.b >> 7) << 1) | (color.a >> 7);
+            (boost, cluster_index, f_pixel::from_rgba(&lut, color))
+        });
+        let colors16 = self.hashmap16.values().map(|&(boost, color)| {
+            let cluster_index = (((color.r >> 15) << 3) | ((color.g >> 15) << 2) | ((color.b >> 15) << 1) | (color.a >> 15)) as u8;
+            (boost, cluster_index, f_pixel::from_rgba16(&lut16, color))
+        });
+        let gamut = self.gamut.as_deref();
+
+        let total_perceptual_weight = colors.chain(colors16).map(|(boost, cluster_index, color)| {
+            let color = match gamut {
+                Some(m) => color.convert_gamut(m),
+                None => color,
+            };
             if boost == 0 && !temp.is_empty() {
                 return 0.;
             }
-            let cluster_index = (((color.r >> 7) << 3) | ((color.g >> 7) << 2) | ((color.b >> 7) << 1) | (color.a >> 7)) as u8;
 
             let weight = (boost as f32 / 170.).min(max_perceptual_weight);
             if weight == 0. {
                 return 0.;
             }
 
-            let color = f_pixel::from_rgba(&lut, color);
-
             // fixed colors are always included in the palette, so it would be wasteful to duplicate them in palette from histogram
             // FIXME: removes fixed colors from histogram (could be done better by marking them as max importance instead)
             for HashColor(fixed) in &self.fixed_colors {
@@ -329,6 +473,7 @@ impl Histogram {
             items,
             clusters,
             total_perceptual_weight,
+            grayscale: self.grayscale,
         }
     }
 }
@@ -358,6 +503,8 @@ pub(crate) struct HistogramInternal {
     pub items: Box<[HistItem]>,
     pub total_perceptual_weight: f64,
     pub clusters: [Cluster; LIQ_MAXCLUSTER],
+    /// All colors are gray, so median cut and K-means only need luma and alpha
+    pub grayscale: bool,
 }
 
 // Pre-grouped colors
@@ -376,6 +523,7 @@ impl std::hash::BuildHasher for RgbaHasher {
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct RgbaHasher(pub u32);
 impl std::hash::Hasher for RgbaHasher {
     // magic constant from fxhash. For a single 32-bit key that's all it needs!
@@ -387,7 +535,8 @@ impl std::hash::Hasher for RgbaHasher {
     fn write(&mut self, _bytes: &[u8]) { unimplemented!() }
     fn write_u8(&mut self, _i: u8) { unimplemented!() }
     fn write_u16(&mut self, _i: u16) { unimplemented!() }
-    fn write_u64(&mut self, _i: u64) { unimplemented!() }
+    #[inline(always)]
+    fn write_u64(&mut self, i: u64) { self.0 = (i as u32) ^ (i >> 32) as u32; }
     fn write_u128(&mut self, _i: u128) { unimplemented!() }
     fn write_usize(&mut self, _i: usize) { unimplemented!() }
     fn write_i8(&mut self, _i: i8) { unimplemented!() }
@@ -400,7 +549,7 @@ impl std::hash::Hasher for RgbaHasher {
 
 /// libstd's HashSet is afraid of NaN
 #[repr(transparent)]
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub(crate) struct HashColor(pub f_pixel);
 
 #[allow(clippy::derive_hash_xor_eq)]
@@ -4,15 +4,25 @@ use crate::error::*;
 use crate::ffi::MagicTag;
 use crate::ffi::LIQ_FREED_MAGIC;
 use crate::ffi::LIQ_IMAGE_MAGIC;
-use crate::pal::{f_pixel, gamma_lut, PalF, MIN_OPAQUE_A, RGBA};
+use crate::pal::{f_pixel, gamma_lut, gray, ColorSpace, ComponentOrder, GrayAlpha8, LinearTransfer, PalF, TransferFunction, MIN_OPAQUE_A, RGBA, RGBA16, RGBAF};
 use crate::remap::DitherMapMode;
-use crate::rows::{DynamicRows, PixelsSource};
+use crate::rows::{temp_buf, DynamicRows, PixelsSource};
 use crate::seacow::RowBitmap;
 use crate::seacow::SeaCow;
+use crate::yuv::{YuvFormat, YuvPlanes, YuvSource};
 use crate::LIQ_HIGH_MEMORY_LIMIT;
 use rgb::ComponentMap;
 use std::mem::MaybeUninit;
 
+/// Position and size of a part of an image, in pixels
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rect {
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
 /// Describes image dimensions for the library.
 pub struct Image<'pixels, 'rows> {
     pub(crate) magic_header: MagicTag,
@@ -35,23 +45,27 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
         pixels: PixelsSource<'pixels, 'rows>,
         width: u32,
         height: u32,
-        gamma: f64,
-    ) -> Result<Self, liq_error> {
+        transfer: TransferFunction,
+    ) -> Result<Self, Error> {
         if !crate::ffi::check_image_size(attr, width, height) {
-            return Err(LIQ_BUFFER_TOO_SMALL);
+            return Err(Error::out_of_range("image size", f64::from(width) * f64::from(height)));
         }
 
-        if !(0. ..=1.).contains(&gamma) {
-            attr.verbose_print("  error: gamma must be >= 0 and <= 1 (try 1/gamma instead)");
-            return Err(LIQ_VALUE_OUT_OF_RANGE);
-        }
+        let transfer = match transfer.normalized() {
+            Ok(t) => t,
+            Err(err) => {
+                attr.verbose_print("  error: gamma must be >= 0 and <= 1 (try 1/gamma instead)");
+                return Err(err);
+            },
+        };
         let img = Image {
             magic_header: LIQ_IMAGE_MAGIC,
             px: DynamicRows::new(
                 width,
                 height,
                 pixels,
-                if gamma > 0. { gamma } else { 0.45455 },
+                transfer,
+                attr.grayscale,
             ),
             importance_map: None,
             edges: None,
@@ -121,22 +135,32 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
     /// Pixels that match the background color will be made transparent if there's a fully transparent color available in the palette.
     ///
     /// The background image's pixels must outlive this image
-    pub fn set_background(&mut self, background: Image<'pixels, 'rows>) -> Result<(), liq_error> {
+    pub fn set_background(&mut self, background: Image<'pixels, 'rows>) -> Result<(), Error> {
         if background.background.is_some() {
-            return Err(LIQ_UNSUPPORTED);
+            return Err(Error::Unsupported("background can't have its own background"));
         }
         if self.px.width != background.px.width || self.px.height != background.px.height {
-            return Err(LIQ_BUFFER_TOO_SMALL);
+            return Err(Error::BufferTooSmall { expected: self.width() * self.height(), actual: background.width() * background.height() });
         }
         self.background = Some(Box::new(background));
         self.dither_map = None;
         Ok(())
     }
 
+    /// Declare RGB primaries of the image, and of the output the palette is made for.
+    ///
+    /// Colors are converted in linear light, so that e.g. palettes for Display P3 content aren't computed as if it was sRGB.
+    /// Colors outside of the output gamut are clipped. The transfer function (gamma) is not changed.
+    ///
+    /// It must be called before the image is quantized.
+    pub fn set_color_space(&mut self, input: ColorSpace, output: ColorSpace) -> Result<(), Error> {
+        self.px.set_gamut(input.gamut_matrix(output))
+    }
+
     /// Set which pixels are more important (and more likely to get a palette entry)
     ///
     /// The map must be `width`×`height` pixels large. Higher numbers = more important.
-    pub fn set_importance_map(&mut self, map: &[u8]) -> Result<(), liq_error> {
+    pub fn set_importance_map(&mut self, map: &[u8]) -> Result<(), Error> {
         self.importance_map = Some(SeaCow::boxed(map.into()));
         Ok(())
     }
@@ -146,6 +170,37 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
         self.importance_map = map;
     }
 
+    /// A region of this image that can be quantized and remapped on its own.
+    ///
+    /// The view reads pixels of this image, and reuses its already converted pixels,
+    /// importance map and contrast maps instead of computing them again.
+    /// If this image has already been quantized, the view can only be remapped.
+    pub fn sub_image(&self, rect: Rect) -> Result<Image<'_, 'rows>, Error> {
+        let check_edge = |name, start: usize, len: usize, max: usize| match start.checked_add(len) {
+            Some(end) if len > 0 && end <= max => Ok(()),
+            _ => Err(Error::out_of_range(name, start.saturating_add(len) as f64)),
+        };
+        check_edge("rect right edge", rect.left, rect.width, self.width())?;
+        check_edge("rect bottom edge", rect.top, rect.height, self.height())?;
+        let crop = |map: &[u8]| -> Box<[u8]> {
+            map.chunks_exact(self.width()).skip(rect.top).take(rect.height)
+                .flat_map(|row| row[rect.left..][..rect.width].iter().copied())
+                .collect()
+        };
+        Ok(Image {
+            magic_header: LIQ_IMAGE_MAGIC,
+            px: self.px.view(rect.left, rect.top, rect.width as u32, rect.height as u32),
+            importance_map: self.importance_map.as_ref().map(|m| SeaCow::boxed(crop(m.as_slice()))),
+            edges: self.edges.as_deref().map(crop),
+            dither_map: self.dither_map.as_deref().map(crop),
+            background: match &self.background {
+                Some(bg) => Some(Box::new(bg.sub_image(rect)?)),
+                None => None,
+            },
+            fixed_colors: self.fixed_colors.clone(),
+        })
+    }
+
     /// Width of the image in pixels
     #[must_use]
     #[inline(always)]
@@ -162,27 +217,32 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
 
     /// Reserves a color in the output palette created from this image. It behaves as if the given color was used in the image and was very important.
     ///
-    /// RGB values of Color are assumed to have the same gamma as the image.
+    /// RGB values of Color are assumed to have the same gamma and color space as the image.
     ///
     /// It must be called before the image is quantized.
     ///
     /// Returns error if more than 256 colors are added. If image is quantized to fewer colors than the number of fixed colors added, then excess fixed colors will be ignored.
-    pub fn add_fixed_color(&mut self, color: RGBA) -> liq_error {
-        if self.fixed_colors.len() > 255 { return LIQ_UNSUPPORTED; }
-        let lut = gamma_lut(self.px.gamma);
-        self.fixed_colors.push(f_pixel::from_rgba(&lut, RGBA {r: color.r, g: color.g, b: color.b, a: color.a}));
-        LIQ_OK
+    pub fn add_fixed_color(&mut self, color: RGBA) -> Result<(), Error> {
+        if self.fixed_colors.len() > 255 { return Err(Error::Unsupported("more than 256 fixed colors")); }
+        let lut = gamma_lut(self.px.transfer);
+        let color = if self.px.grayscale { gray(color) } else { color };
+        let px = f_pixel::from_rgba(&lut, RGBA {r: color.r, g: color.g, b: color.b, a: color.a});
+        self.fixed_colors.push(match &self.px.gamut {
+            Some(m) => px.convert_gamut(m),
+            None => px,
+        });
+        Ok(())
     }
 
     #[inline(always)]
-    pub(crate) fn gamma(&self) -> f64 {
-        self.px.gamma
+    pub(crate) fn transfer(&self) -> TransferFunction {
+        self.px.transfer
     }
 
     /// Builds two maps:
     ///    importance_map - approximation of areas with high-frequency noise, except straight edges. 1=flat, 0=noisy.
     ///    edges - noise map including all edges
-    pub(crate) fn contrast_maps(&mut self) -> Result<(), liq_error> {
+    pub(crate) fn contrast_maps(&mut self) -> Result<(), Error> {
         let width = self.width();
         let height = self.height();
         if width < 4 || height < 4 || (3 * width * height) > LIQ_HIGH_MEMORY_LIMIT {
@@ -194,16 +254,19 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
 
         let mut tmp = vec![0; width * height];
 
-        let mut rows_iter = self.px.all_rows_f()?.chunks_exact(width);
-
-        let mut next_row = rows_iter.next().unwrap();
-        let mut curr_row = next_row;
-        let mut prev_row;
+        if !self.px.is_indexed() {
+            let _ = self.px.all_rows_f()?;
+        }
+        let px = &self.px;
+        let lut = gamma_lut(px.transfer);
+        let temp_width = px.temp_row_width();
+        let mut temp_row = temp_buf(temp_width);
+        let (mut prev_buf, mut curr_buf, mut next_buf) = (temp_buf(temp_width), temp_buf(temp_width), temp_buf(temp_width));
 
-        for (noise_row, edges_row) in noise.chunks_exact_mut(width).zip(edges.chunks_exact_mut(width)) {
-            prev_row = curr_row;
-            curr_row = next_row;
-            next_row = rows_iter.next().unwrap_or(next_row);
+        for (y, (noise_row, edges_row)) in noise.chunks_exact_mut(width).zip(edges.chunks_exact_mut(width)).enumerate() {
+            let prev_row = px.row_f_shared(&mut temp_row, &mut prev_buf, y.saturating_sub(1), &lut);
+            let curr_row = px.row_f_shared(&mut temp_row, &mut curr_buf, y, &lut);
+            let next_row = px.row_f_shared(&mut temp_row, &mut next_buf, (y + 1).min(height - 1), &lut);
             let mut prev;
             let mut curr = curr_row[0].0;
             let mut next = curr;
@@ -248,9 +311,10 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
     ///
     /// See the [`rgb`] and [`bytemuck`](//lib.rs/bytemuck) crates for making `[RGBA]` slices from `[u8]` slices.
     ///
-    /// Use `0.` for gamma if the image is sRGB (most images are).
+    /// Use `0.` for gamma if the image is sRGB (most images are). That's an approximation of sRGB with a power function.
+    /// For the exact curve, or other transfer functions, pass a [`TransferFunction`] instead.
     #[inline(always)]
-    pub fn new(attr: &Attributes, pixels: &'pixels [RGBA], width: usize, height: usize, gamma: f64) -> Result<Self, liq_error> {
+    pub fn new(attr: &Attributes, pixels: &'pixels [RGBA], width: usize, height: usize, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
         Self::new_stride(attr, pixels, width, height, width, gamma)
     }
 
@@ -264,35 +328,195 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
     ///
     /// This function is marked as unsafe, because the callback function MUST initialize the entire row (call `write` on every `MaybeUninit` pixel).
     ///
-    pub unsafe fn new_fn<F: 'static + Fn(&mut [MaybeUninit<RGBA>], usize) + Send + Sync>(attr: &Attributes, convert_row_fn: F, width: usize, height: usize, gamma: f64) -> Result<Self, liq_error> {
-        Image::new_internal(attr, PixelsSource::Callback(Box::new(convert_row_fn)), width as u32, height as u32, gamma)
+    pub unsafe fn new_fn<F: 'static + Fn(&mut [MaybeUninit<RGBA>], usize) + Send + Sync>(attr: &Attributes, convert_row_fn: F, width: usize, height: usize, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        Image::new_internal(attr, PixelsSource::Callback(Box::new(convert_row_fn)), width as u32, height as u32, gamma.into())
     }
 
     /// Stride is in pixels. Allows defining regions of larger images or images with padding without copying.
     ///
     /// Otherwise the same as [`Image::new`].
     #[inline(always)]
-    pub fn new_stride(attr: &Attributes, pixels: &'pixels [RGBA], width: usize, height: usize, stride: usize, gamma: f64) -> Result<Self, liq_error> {
-        Self::new_stride_internal(attr, SeaCow::borrowed(pixels), width, height, stride, gamma)
+    pub fn new_stride(attr: &Attributes, pixels: &'pixels [RGBA], width: usize, height: usize, stride: usize, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        Self::new_stride_internal(attr, SeaCow::borrowed(pixels), width, height, stride, ComponentOrder::RGBA, gamma)
     }
 
     /// Create new image by copying `pixels` to an internal buffer, so that it makes a self-contained type.
     ///
     /// Otherwise the same as [`Image::new_stride`].
     #[inline]
-    pub fn new_stride_copy(attr: &Attributes, pixels: &[RGBA], width: usize, height: usize, stride: usize, gamma: f64) -> Result<Image<'static, 'static>, liq_error> {
-        Self::new_stride_internal(attr, SeaCow::boxed(pixels.into()), width, height, stride, gamma)
+    pub fn new_stride_copy(attr: &Attributes, pixels: &[RGBA], width: usize, height: usize, stride: usize, gamma: impl Into<TransferFunction>) -> Result<Image<'static, 'static>, Error> {
+        Self::new_stride_internal(attr, SeaCow::boxed(pixels.into()), width, height, stride, ComponentOrder::RGBA, gamma)
+    }
+
+    /// Describe 32-bit pixels stored in a channel order other than RGBA, e.g. BGRA from Windows/Cairo surfaces.
+    ///
+    /// Pixels are swizzled on the fly, so there's no need to make an RGBA copy of the image.
+    ///
+    /// Otherwise the same as [`Image::new`].
+    #[inline(always)]
+    pub fn new_ordered(attr: &Attributes, pixels: &'pixels [u8], width: usize, height: usize, order: ComponentOrder, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        Self::new_stride_ordered(attr, pixels, width, height, width, order, gamma)
+    }
+
+    /// Stride is in pixels, not bytes.
+    ///
+    /// Otherwise the same as [`Image::new_ordered`].
+    pub fn new_stride_ordered(attr: &Attributes, pixels: &'pixels [u8], width: usize, height: usize, stride: usize, order: ComponentOrder, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        // RGBA8 has alignment of 1, so any 4 bytes can be stored in it
+        let pixels = unsafe { std::slice::from_raw_parts(pixels.as_ptr().cast::<RGBA>(), pixels.len() / 4) };
+        Self::new_stride_internal(attr, SeaCow::borrowed(pixels), width, height, stride, order, gamma)
+    }
+
+    /// Describe dimensions of a slice of 16-bit RGBA pixels.
+    ///
+    /// Pixels are converted to the library's internal floating-point format with full precision,
+    /// so smooth gradients don't lose precision as they would if the image was reduced to 8 bits first.
+    /// See [`Attributes::set_histogram_precision`].
+    ///
+    /// Use `0.` for gamma if the image is sRGB (most images are).
+    #[inline(always)]
+    pub fn new_rgba16(attr: &Attributes, pixels: &'pixels [RGBA16], width: usize, height: usize, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        Self::new_stride_rgba16(attr, pixels, width, height, width, gamma)
+    }
+
+    /// Stride is in pixels.
+    ///
+    /// Otherwise the same as [`Image::new_rgba16`].
+    pub fn new_stride_rgba16(attr: &Attributes, pixels: &'pixels [RGBA16], width: usize, height: usize, stride: usize, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        if pixels.len() < (stride * height + width - stride) {
+            attr.verbose_print(format!("Buffer length is {} bytes, which is not enough for {}×{}×8 RGBA16 bytes", pixels.len()*8, stride, height));
+            return Err(Error::BufferTooSmall { expected: stride * height + width - stride, actual: pixels.len() });
+        }
+
+        let rows = SeaCow::boxed(pixels.chunks(stride).map(|row| row.as_ptr()).collect());
+        Image::new_internal(attr, PixelsSource::Pixels16 { rows }, width as u32, height as u32, gamma.into())
+    }
+
+    /// Describe dimensions of a slice of linear-light floating-point pixels, e.g. from a render's framebuffer.
+    ///
+    /// The `transfer` decides how values are clipped or tone-mapped, and which gamma the palette is made for.
+    /// Pixels are converted to the library's internal format directly, without an 8-bit intermediate.
+    #[inline(always)]
+    pub fn new_linear_f32(attr: &Attributes, pixels: &'pixels [RGBAF], width: usize, height: usize, transfer: LinearTransfer) -> Result<Self, Error> {
+        Self::new_stride_linear_f32(attr, pixels, width, height, width, transfer)
+    }
+
+    /// Stride is in pixels.
+    ///
+    /// Otherwise the same as [`Image::new_linear_f32`].
+    pub fn new_stride_linear_f32(attr: &Attributes, pixels: &'pixels [RGBAF], width: usize, height: usize, stride: usize, transfer: LinearTransfer) -> Result<Self, Error> {
+        if pixels.len() < (stride * height + width - stride) {
+            attr.verbose_print(format!("Buffer length is {} bytes, which is not enough for {}×{}×16 RGBA f32 bytes", pixels.len()*16, stride, height));
+            return Err(Error::BufferTooSmall { expected: stride * height + width - stride, actual: pixels.len() });
+        }
+        let output_transfer = transfer.output_transfer();
+
+        let rows = SeaCow::boxed(pixels.chunks(stride).map(|row| row.as_ptr()).collect());
+        Image::new_internal(attr, PixelsSource::PixelsF32 { rows, transfer }, width as u32, height as u32, output_transfer)
+    }
+
+    /// Describe dimensions of a slice of 8-bit grayscale pixels, one byte per pixel.
+    ///
+    /// Gray pixels give only gray colors in the palette. To reduce color images to gray, see [`Attributes::set_grayscale`].
+    ///
+    /// Use `0.` for gamma if the image is sRGB (most images are).
+    #[inline(always)]
+    pub fn new_gray8(attr: &Attributes, pixels: &'pixels [u8], width: usize, height: usize, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        Self::new_stride_gray8(attr, pixels, width, height, width, gamma)
+    }
+
+    /// Stride is in pixels.
+    ///
+    /// Otherwise the same as [`Image::new_gray8`].
+    pub fn new_stride_gray8(attr: &Attributes, pixels: &'pixels [u8], width: usize, height: usize, stride: usize, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        if pixels.len() < (stride * height + width - stride) {
+            attr.verbose_print(format!("Buffer length is {} bytes, which is not enough for {}×{} gray bytes", pixels.len(), stride, height));
+            return Err(Error::BufferTooSmall { expected: stride * height + width - stride, actual: pixels.len() });
+        }
+
+        let rows = SeaCow::boxed(pixels.chunks(stride).map(|row| row.as_ptr()).collect());
+        Image::new_internal(attr, PixelsSource::Gray { rows, has_alpha: false }, width as u32, height as u32, gamma.into())
+    }
+
+    /// Describe dimensions of a slice of 8-bit gray+alpha pixels.
+    ///
+    /// Otherwise the same as [`Image::new_gray8`].
+    #[inline(always)]
+    pub fn new_gray_alpha8(attr: &Attributes, pixels: &'pixels [GrayAlpha8], width: usize, height: usize, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        Self::new_stride_gray_alpha8(attr, pixels, width, height, width, gamma)
+    }
+
+    /// Stride is in pixels.
+    ///
+    /// Otherwise the same as [`Image::new_gray_alpha8`].
+    pub fn new_stride_gray_alpha8(attr: &Attributes, pixels: &'pixels [GrayAlpha8], width: usize, height: usize, stride: usize, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        if pixels.len() < (stride * height + width - stride) {
+            attr.verbose_print(format!("Buffer length is {} bytes, which is not enough for {}×{}×2 gray+alpha bytes", pixels.len()*2, stride, height));
+            return Err(Error::BufferTooSmall { expected: stride * height + width - stride, actual: pixels.len() });
+        }
+
+        // GrayAlpha8 is two bytes with alignment of 1
+        let rows = SeaCow::boxed(pixels.chunks(stride).map(|row| row.as_ptr().cast::<u8>()).collect());
+        Image::new_internal(attr, PixelsSource::Gray { rows, has_alpha: true }, width as u32, height as u32, gamma.into())
+    }
+
+    /// Describe an image that already has a palette, e.g. to reduce a 256-color PNG to fewer colors.
+    ///
+    /// `indices` are one byte per pixel. Indices beyond the end of the `palette` are transparent black.
+    /// The histogram is made from counts of the indices, and the pixels are looked up in the palette
+    /// only when they're needed, so the image is never expanded to RGBA in memory.
+    ///
+    /// Use `0.` for gamma if the image is sRGB (most images are).
+    #[inline(always)]
+    pub fn new_indexed(attr: &Attributes, palette: &[RGBA], indices: &'pixels [u8], width: usize, height: usize, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        Self::new_stride_indexed(attr, palette, indices, width, height, width, gamma)
+    }
+
+    /// Stride is in pixels (bytes).
+    ///
+    /// Otherwise the same as [`Image::new_indexed`].
+    pub fn new_stride_indexed(attr: &Attributes, palette: &[RGBA], indices: &'pixels [u8], width: usize, height: usize, stride: usize, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        if palette.len() > 256 {
+            attr.verbose_print(format!("  error: palette has {} colors, but indices can refer to only 256", palette.len()));
+            return Err(Error::out_of_range("palette length", palette.len() as f64));
+        }
+        if indices.len() < (stride * height + width - stride) {
+            attr.verbose_print(format!("Buffer length is {} bytes, which is not enough for {}×{} index bytes", indices.len(), stride, height));
+            return Err(Error::BufferTooSmall { expected: stride * height + width - stride, actual: indices.len() });
+        }
+
+        let mut padded_palette = Box::new([RGBA::new(0, 0, 0, 0); 256]);
+        padded_palette[..palette.len()].copy_from_slice(palette);
+        let rows = SeaCow::boxed(indices.chunks(stride).map(|row| row.as_ptr()).collect());
+        Image::new_internal(attr, PixelsSource::Indexed { rows, palette: padded_palette }, width as u32, height as u32, gamma.into())
+    }
+
+    /// Describe a video frame in planar or semi-planar YUV (YCbCr), e.g. I420 or NV12.
+    ///
+    /// Rows are converted to RGBA as they're needed, so a full RGBA copy of the frame is never made.
+    /// Chroma is upsampled by repeating the nearest sample.
+    ///
+    /// Use `0.` for gamma if the video is sRGB-like (most videos are).
+    pub fn new_yuv(attr: &Attributes, planes: YuvPlanes<'pixels>, width: usize, height: usize, format: YuvFormat, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        let yuv = match YuvSource::new(planes, width, height, format) {
+            Ok(yuv) => yuv,
+            Err(err) => {
+                attr.verbose_print(format!("  error: YUV planes don't match {}×{} {:?} frame", width, height, format.subsampling));
+                return Err(err);
+            },
+        };
+        Image::new_internal(attr, PixelsSource::Yuv(Box::new(yuv)), width as u32, height as u32, gamma.into())
     }
 
-    fn new_stride_internal<'a>(attr: &Attributes, pixels: SeaCow<'a, RGBA>, width: usize, height: usize, stride: usize, gamma: f64) -> Result<Image<'a, 'static>, liq_error> {
+    fn new_stride_internal<'a>(attr: &Attributes, pixels: SeaCow<'a, RGBA>, width: usize, height: usize, stride: usize, order: ComponentOrder, gamma: impl Into<TransferFunction>) -> Result<Image<'a, 'static>, Error> {
         let slice = pixels.as_slice();
         if slice.len() < (stride * height + width - stride) {
             attr.verbose_print(format!("Buffer length is {} bytes, which is not enough for {}×{}×4 RGBA bytes", slice.len()*4, stride, height));
-            return Err(LIQ_BUFFER_TOO_SMALL);
+            return Err(Error::BufferTooSmall { expected: stride * height + width - stride, actual: slice.len() });
         }
 
         let rows = SeaCow::boxed(slice.chunks(stride).map(|row| row.as_ptr()).collect());
-        Image::new_internal(attr, PixelsSource::Pixels { rows, pixels: Some(pixels) }, width as u32, height as u32, gamma)
+        Image::new_internal(attr, PixelsSource::Pixels { rows, pixels: Some(pixels), order }, width as u32, height as u32, gamma.into())
     }
 }
 
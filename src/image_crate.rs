@@ -0,0 +1,88 @@
+//! Conversions between the [`image`](https://lib.rs/image) crate's buffers and this library's types.
+//!
+//! Requires the `image` cargo feature.
+
+use crate::attr::Attributes;
+use crate::error::*;
+use crate::image::Image;
+use crate::pal::RGBA;
+use crate::quant::QuantizationResult;
+use ::image::{DynamicImage, GrayImage, RgbImage, RgbaImage};
+use rgb::FromSlice;
+
+impl<'pixels> Image<'pixels, 'static> {
+    /// Borrows the image's pixels. Images are assumed to be sRGB.
+    pub fn from_rgba_image(attr: &Attributes, image: &'pixels RgbaImage) -> Result<Self, Error> {
+        Self::new(attr, image.as_raw().as_rgba(), image.width() as usize, image.height() as usize, 0.)
+    }
+
+    /// RGB pixels are copied to RGBA. Images are assumed to be sRGB.
+    pub fn from_rgb_image(attr: &Attributes, image: &RgbImage) -> Result<Self, Error> {
+        let pixels: Vec<RGBA> = image.as_raw().as_rgb().iter().map(|px| px.with_alpha(255)).collect();
+        let width = image.width() as usize;
+        Image::new_stride_copy(attr, &pixels, width, image.height() as usize, width, 0.)
+    }
+
+    /// 8-bit RGBA, gray and 16-bit RGBA images are borrowed, other formats are converted to 8-bit RGBA.
+    /// Images are assumed to be sRGB.
+    pub fn from_dynamic_image(attr: &Attributes, image: &'pixels DynamicImage) -> Result<Self, Error> {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        match image {
+            DynamicImage::ImageRgba8(img) => Self::from_rgba_image(attr, img),
+            DynamicImage::ImageRgb8(img) => Self::from_rgb_image(attr, img),
+            DynamicImage::ImageLuma8(img) => Self::new_gray8(attr, img.as_raw(), width, height, 0.),
+            DynamicImage::ImageLumaA8(img) => Self::new_gray_alpha8(attr, img.as_raw().as_gray_alpha(), width, height, 0.),
+            DynamicImage::ImageRgba16(img) => Self::new_rgba16(attr, img.as_raw().as_rgba(), width, height, 0.),
+            other => {
+                let rgba = other.to_rgba8();
+                Image::new_stride_copy(attr, rgba.as_raw().as_rgba(), width, height, width, 0.)
+            },
+        }
+    }
+}
+
+impl QuantizationResult {
+    /// Remaps the image to a grayscale buffer of palette indices, and returns it with the palette.
+    pub fn remapped_luma_image(&mut self, image: &mut Image<'_, '_>) -> Result<(Vec<RGBA>, GrayImage), Error> {
+        let (width, height) = (image.width() as u32, image.height() as u32);
+        let (palette, indices) = self.remapped(image)?;
+        let actual = indices.len();
+        let indices = GrayImage::from_raw(width, height, indices).ok_or(Error::BufferTooSmall { expected: image.width() * image.height(), actual })?;
+        Ok((palette, indices))
+    }
+
+    /// Remaps the image and expands the palette back to RGBA pixels, e.g. to preview the result.
+    pub fn remapped_rgba_image(&mut self, image: &mut Image<'_, '_>) -> Result<RgbaImage, Error> {
+        let (width, height) = (image.width() as u32, image.height() as u32);
+        let (palette, indices) = self.remapped(image)?;
+        let pixels: Vec<u8> = indices.iter().flat_map(|&i| {
+            let px = palette[i as usize];
+            [px.r, px.g, px.b, px.a]
+        }).collect();
+        let actual = pixels.len();
+        RgbaImage::from_raw(width, height, pixels).ok_or(Error::BufferTooSmall { expected: image.width() * image.height() * 4, actual })
+    }
+}
+
+#[test]
+fn image_crate() {
+    let mut liq = Attributes::new();
+    let rgb = RgbImage::from_fn(8, 4, |x, y| ::image::Rgb([x as u8 * 30, y as u8 * 60, 100]));
+    let expected: Vec<RGBA> = rgb.pixels().map(|px| RGBA::new(px[0], px[1], px[2], 255)).collect();
+
+    let dynamic = DynamicImage::ImageRgb8(rgb.clone());
+    for mut img in [Image::from_rgb_image(&liq, &rgb).unwrap(), Image::from_dynamic_image(&liq, &dynamic).unwrap()] {
+        let mut res = liq.quantize(&mut img).unwrap();
+        let preview = res.remapped_rgba_image(&mut img).unwrap();
+        assert_eq!(expected, preview.as_raw().as_rgba());
+
+        let (palette, indices) = res.remapped_luma_image(&mut img).unwrap();
+        assert_eq!((8, 4), indices.dimensions());
+        assert!(indices.pixels().zip(&expected).all(|(i, px)| palette[i[0] as usize] == *px));
+    }
+
+    let gray = DynamicImage::ImageLuma8(GrayImage::from_fn(4, 4, |x, _| ::image::Luma([x as u8 * 80])));
+    let mut img = Image::from_dynamic_image(&liq, &gray).unwrap();
+    let preview = liq.quantize(&mut img).unwrap().remapped_rgba_image(&mut img).unwrap();
+    assert_eq!(&[240, 240, 240, 255], &preview.get_pixel(3, 3).0);
+}
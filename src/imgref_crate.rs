@@ -0,0 +1,68 @@
+//! Strided 2-D buffers from the [`imgref`](https://lib.rs/imgref) crate.
+//!
+//! Requires the `imgref` cargo feature.
+
+use crate::attr::Attributes;
+use crate::error::*;
+use crate::image::Image;
+use crate::pal::{TransferFunction, RGBA};
+use crate::quant::QuantizationResult;
+use crate::seacow::RowBitmapMut;
+use imgref::{ImgRef, ImgRefMut};
+use std::mem::MaybeUninit;
+
+impl<'pixels> Image<'pixels, 'static> {
+    /// Borrows pixels of the image, using its stride. The image can be a sub-region of a larger buffer.
+    ///
+    /// Otherwise the same as [`Image::new_stride`].
+    #[inline]
+    pub fn from_imgref(attr: &Attributes, img: ImgRef<'pixels, RGBA>, gamma: impl Into<TransferFunction>) -> Result<Self, Error> {
+        Self::new_stride(attr, img.buf(), img.width(), img.height(), img.stride(), gamma)
+    }
+}
+
+impl QuantizationResult {
+    /// Remap image into an existing buffer that may have padding between rows.
+    ///
+    /// The buffer must have the same width and height as the image. Padding is left untouched.
+    ///
+    /// Otherwise the same as [`QuantizationResult::remap_into`].
+    pub fn remap_into_imgref(&mut self, image: &mut Image<'_, '_>, mut output: ImgRefMut<'_, MaybeUninit<u8>>) -> Result<(), Error> {
+        if output.width() != image.width() {
+            return Err(Error::out_of_range("output width", output.width() as f64));
+        }
+        if output.height() != image.height() {
+            return Err(Error::out_of_range("output height", output.height() as f64));
+        }
+        let (width, height, stride) = (output.width(), output.height(), output.stride());
+        let actual = output.buf().len();
+        let rows = RowBitmapMut::new_strided(output.buf_mut(), width, height, stride)
+            .ok_or(Error::BufferTooSmall { expected: stride * (height - 1) + width, actual })?;
+        self.write_remapped_image_rows_internal(image, rows)
+    }
+}
+
+#[test]
+fn imgref_stride() {
+    let mut liq = Attributes::new();
+    let pixels: Vec<RGBA> = (0..12 * 5).map(|i| RGBA::new(i as u8 * 4, 50, 255 - i as u8, 255)).collect();
+    let full = ImgRef::new_stride(&pixels[..], 10, 5, 12);
+    let sub = full.sub_image(2, 1, 6, 3);
+    let mut img = Image::from_imgref(&liq, sub, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+
+    let mut out = [MaybeUninit::new(255u8); 9 * 2 + 6];
+    res.remap_into_imgref(&mut img, ImgRefMut::new_stride(&mut out[..], 6, 3, 9)).unwrap();
+    let out: Vec<u8> = out.iter().map(|px| unsafe { px.assume_init() }).collect();
+    let palette = res.palette();
+    for (y, row) in sub.rows().enumerate() {
+        for (x, px) in row.iter().enumerate() {
+            assert_eq!(*px, palette[out[y * 9 + x] as usize]);
+        }
+        if y < 2 {
+            assert!(out[y * 9 + 6..y * 9 + 9].iter().all(|&b| b == 255));
+        }
+    }
+
+    assert_eq!(Err(Error::out_of_range("output width", 2)), res.remap_into_imgref(&mut img, ImgRefMut::new(&mut [MaybeUninit::new(0u8); 4][..], 2, 2)));
+}
@@ -1,16 +1,21 @@
+use crate::cancel::CancellationToken;
 use crate::hist::{HistItem, HistogramInternal};
 use crate::nearest::Nearest;
 use crate::pal::{PalF, PalIndex, PalPop, f_pixel};
-use rayon::iter::ParallelIterator;
-use rayon::slice::ParallelSliceMut;
+use crate::threads::Threads;
+#[cfg(not(feature = "threads"))]
+use crate::threads::ThreadLocal;
 use rgb::alt::ARGB;
 use rgb::ComponentMap;
 use std::cell::RefCell;
+#[cfg(feature = "threads")]
 use thread_local::ThreadLocal;
 
 pub(crate) struct Kmeans {
     averages: Vec<ColorAvg>,
     weighed_diff_sum: f64,
+    /// Only luma and alpha are averaged, and colors stay neutral
+    grayscale: bool,
 }
 
 #[derive(Copy, Clone, Default)]
@@ -22,17 +27,23 @@ struct ColorAvg {
 /// K-Means iteration: new palette color is computed from weighted average of colors that map best to that palette entry.
 impl Kmeans {
     #[inline]
-    pub fn new(pal_len: usize) -> Self {
+    pub fn new(pal_len: usize, grayscale: bool) -> Self {
         Self {
             averages: vec![ColorAvg::default(); pal_len],
             weighed_diff_sum: 0.,
+            grayscale,
         }
     }
 
     #[inline]
     pub fn update_color(&mut self, px: f_pixel, value: f32, matched: PalIndex) {
         let c = &mut self.averages[matched as usize];
-        c.sum += (px.0 * value).map(|c| c as f64);
+        if self.grayscale {
+            c.sum.g += (px.g * value) as f64;
+            c.sum.a += (px.a * value) as f64;
+        } else {
+            c.sum += (px.0 * value).map(|c| c as f64);
+        }
         c.total += value as f64;
     }
 
@@ -41,14 +52,19 @@ impl Kmeans {
             let total = avg.total;
             *pop = PalPop::new(total as f32);
             if total > 0. {
-                *color = avg.sum.map(move |c| (c / total) as f32).into();
+                *color = if self.grayscale {
+                    f_pixel::neutral((avg.sum.g / total) as f32, (avg.sum.a / total) as f32)
+                } else {
+                    avg.sum.map(move |c| (c / total) as f32).into()
+                };
             }
         }
         self.weighed_diff_sum
     }
 
     #[inline(never)]
-    pub(crate) fn iteration(hist: &mut HistogramInternal, palette: &mut PalF, adjust_weight: bool) -> f64 {
+    /// If cancelled, batches are skipped, and the result is meaningless
+    pub(crate) fn iteration(hist: &mut HistogramInternal, palette: &mut PalF, adjust_weight: bool, cancel: &CancellationToken, threads: &Threads) -> f64 {
         if hist.items.is_empty() {
             return 0.;
         }
@@ -59,10 +75,14 @@ impl Kmeans {
 
         let tls = ThreadLocal::new();
         let total = hist.total_perceptual_weight;
+        let grayscale = hist.grayscale;
 
         // chunk size is a trade-off between parallelization and overhead
-        hist.items.par_chunks_mut(256).for_each(|batch| {
-            let kmeans = tls.get_or(move || RefCell::new(Kmeans::new(len)));
+        threads.for_each_chunk(&mut hist.items, 256, |batch| {
+            if cancel.is_cancelled() {
+                return;
+            }
+            let kmeans = tls.get_or(move || RefCell::new(Kmeans::new(len, grayscale)));
             kmeans.borrow_mut().iterate_batch(batch, &n, colors, adjust_weight);
         });
 
@@ -10,27 +10,67 @@ pub mod ffi;
 
 mod attr;
 mod blur;
+mod cancel;
 mod error;
+mod event;
+#[cfg(feature = "gif")]
+mod gif_crate;
 mod hist;
 mod image;
+#[cfg(feature = "image")]
+mod image_crate;
+#[cfg(feature = "imgref")]
+mod imgref_crate;
 mod kmeans;
 mod mediancut;
 mod nearest;
+mod packed;
 mod pal;
+mod palette_file;
+#[cfg(feature = "png")]
+mod png_crate;
+mod preset;
 mod quant;
 mod remap;
 mod rows;
 mod seacow;
+mod stats;
+mod threads;
+mod yuv;
 
 pub use attr::Attributes;
+pub use attr::AttributesBuilder;
 pub use attr::ControlFlow;
+pub use cancel::CancellationToken;
 pub use error::liq_error;
+pub use error::{Error, Stage};
+pub use event::Event;
+#[cfg(feature = "gif")]
+pub use gif_crate::{Disposal, GifWriter};
 pub use hist::Histogram;
 pub use hist::HistogramEntry;
+pub use image::Rect;
+pub use packed::{BitOrder, PackOptions, PackedFormat};
 pub type Image<'pixels> = image::Image<'pixels, 'static>;
+pub use pal::ColorSpace;
+pub use pal::ComponentOrder;
+pub use pal::GrayAlpha8;
 pub use pal::Palette;
 pub use pal::RGBA;
+pub use pal::RGBA16;
+pub use pal::RGBAF;
+pub use pal::LinearTransfer;
+pub use pal::TransferFunction;
+pub use palette_file::{read_palette, write_palette, PaletteFormat, PaletteParseError};
+#[cfg(feature = "png")]
+pub use png_crate::PngOptions;
+pub use preset::Preset;
 pub use quant::QuantizationResult;
+pub use quant::RemapSettings;
+pub use remap::DitherMapMode;
+pub use remap::RemapSink;
+pub use stats::{QuantizationStats, TrialStats};
+pub use yuv::{ChromaSubsampling, YuvFormat, YuvMatrix, YuvPlanes, YuvRange};
 
 const LIQ_HIGH_MEMORY_LIMIT: usize = 1 << 26;
 pub const LIQ_VERSION: u32 = 40000;
@@ -68,6 +108,296 @@ fn takes_rgba() {
     assert!(liq.new_image(&img, 4, 3, 0.0).is_err());
 }
 
+#[test]
+fn component_order() {
+    let mut liq = Attributes::new();
+    let bgra = [0x30, 0x20, 0x10, 0xFF, 0xFF, 0xEE, 0xDD, 0x80];
+    let argb = [0xFF, 0x10, 0x20, 0x30, 0x80, 0xDD, 0xEE, 0xFF];
+    let abgr = [0xFF, 0x30, 0x20, 0x10, 0x80, 0xFF, 0xEE, 0xDD];
+
+    let rgba = [RGBA::new(0x10, 0x20, 0x30, 0xFF), RGBA::new(0xDD, 0xEE, 0xFF, 0x80)];
+    let mut img = liq.new_image(&rgba, 2, 1, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let (_, expected) = res.remapped(&mut img).unwrap();
+
+    for (bytes, order) in [(&bgra, ComponentOrder::BGRA), (&argb, ComponentOrder::ARGB), (&abgr, ComponentOrder::ABGR)] {
+        assert_eq!(rgba[1], order.unpack([bytes[4], bytes[5], bytes[6], bytes[7]]));
+        let mut img = Image::new_ordered(&liq, bytes, 2, 1, order, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        let (pal, px) = res.remapped(&mut img).unwrap();
+        assert_eq!(expected, px);
+        assert_eq!(pal.iter().map(|&c| order.pack(c)).collect::<Vec<_>>(), res.palette_ordered(order));
+        assert!(res.palette_ordered(order).iter().any(|c| c[..] == bytes[..4]));
+    }
+    assert!(Image::new_ordered(&liq, &bgra, 3, 1, ComponentOrder::BGRA, 0.).is_err());
+}
+
+#[test]
+fn rgba16() {
+    let mut liq = Attributes::new();
+    assert!(liq.set_histogram_precision(17).is_err());
+    liq.set_histogram_precision(16).unwrap();
+
+    let px8: Vec<_> = (0..64u8).map(|i| RGBA::new(i * 4, 255 - i, i / 2, if i < 8 { 0 } else { 255 })).collect();
+    let px16: Vec<_> = px8.iter().map(|px| RGBA16::new(px.r as u16 * 257, px.g as u16 * 257, px.b as u16 * 257, px.a as u16 * 257)).collect();
+
+    let mut img8 = liq.new_image(&px8, 8, 8, 0.).unwrap();
+    let mut res8 = liq.quantize(&mut img8).unwrap();
+    let mut img16 = Image::new_rgba16(&liq, &px16, 8, 8, 0.).unwrap();
+    let mut res16 = liq.quantize(&mut img16).unwrap();
+    let expand = |(pal, px): (Vec<RGBA>, Vec<u8>)| px.into_iter().map(|i| pal[i as usize]).collect::<Vec<_>>();
+    assert_eq!(expand(res8.remapped(&mut img8).unwrap()), expand(res16.remapped(&mut img16).unwrap()));
+
+    // colors that differ only below 8 bits are still told apart
+    let fine: Vec<_> = (0..64u16).map(|i| RGBA16::new(0x8000 + i * 16, 0x8000, 0x8000, 0xFFFF)).collect();
+    let mut img = Image::new_rgba16(&liq, &fine, 64, 1, 0.).unwrap();
+    let mut hist = Histogram::new(&liq);
+    hist.add_image(&liq, &mut img).unwrap();
+    assert_eq!(64, hist.finalize_builder(0.45455.into(), 0.).items.len());
+    assert!(Image::new_rgba16(&liq, &fine, 65, 1, 0.).is_err());
+}
+
+#[test]
+fn linear_f32() {
+    let mut liq = Attributes::new();
+    let px8: Vec<_> = (0..64u8).map(|i| RGBA::new(i * 4, 255 - i, 100, 255)).collect();
+    let linear: Vec<_> = px8.iter().map(|px| {
+        let c = |c: u8| TransferFunction::Srgb.decode(c as f64 / 255.) as f32;
+        RGBAF::new(c(px.r), c(px.g), c(px.b), 1.)
+    }).collect();
+
+    let mut img = Image::new_linear_f32(&liq, &linear, 8, 8, LinearTransfer::Srgb).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let (pal, idx) = res.remapped(&mut img).unwrap();
+    for (px, i) in px8.iter().zip(idx) {
+        let q = pal[i as usize];
+        assert!(px.r.abs_diff(q.r) <= 1 && px.g.abs_diff(q.g) <= 1 && px.b.abs_diff(q.b) <= 1, "{:?} {:?}", px, q);
+    }
+
+    let hdr = vec![RGBAF::new(8., 20., 100., 1.); 16];
+    let mut img = Image::new_linear_f32(&liq, &hdr, 4, 4, LinearTransfer::Srgb).unwrap();
+    assert_eq!(&[RGBA::new(255, 255, 255, 255)], liq.quantize(&mut img).unwrap().palette());
+
+    let tone_map = LinearTransfer::ToneMap(std::sync::Arc::new(|c| c / (c + 1.)));
+    let mut img = Image::new_linear_f32(&liq, &hdr, 4, 4, tone_map).unwrap();
+    let pal = liq.quantize(&mut img).unwrap().palette_vec();
+    assert_eq!(1, pal.len());
+    assert!(pal[0].r < pal[0].g && pal[0].g < pal[0].b && pal[0].b < 255, "{:?}", pal);
+}
+
+#[test]
+fn transfer_functions() {
+    for t in [TransferFunction::Srgb, TransferFunction::Gamma(0.45455), TransferFunction::Linear, TransferFunction::Bt709, TransferFunction::Pq] {
+        for i in 0..=100 {
+            let v = i as f64 / 100.;
+            assert!((t.encode(t.decode(v)) - v).abs() < 1e-6, "{:?} {}", t, v);
+        }
+    }
+    assert!((TransferFunction::Srgb.decode(0.5) - 0.214).abs() < 0.001);
+
+    let mut liq = Attributes::new();
+    let dark: Vec<_> = (0..64u8).map(|i| RGBA::new(i, i / 2, 63 - i, 255)).collect();
+    let mut img = Image::new(&liq, &dark, 8, 8, TransferFunction::Srgb).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    assert_eq!(TransferFunction::Srgb, res.output_transfer());
+    let (pal, idx) = res.remapped(&mut img).unwrap();
+    for (px, i) in dark.iter().zip(idx) {
+        assert_eq!(*px, pal[i as usize]);
+    }
+
+    res.set_output_gamma(TransferFunction::Linear).unwrap();
+    assert_eq!(1., res.output_gamma());
+    let (pal, _) = res.remapped(&mut img).unwrap();
+    assert!(pal.iter().all(|c| c.r <= 13 && c.b <= 13), "{:?}", pal);
+    assert!(res.set_output_gamma(1.5).is_err());
+    assert!(Image::new(&liq, &dark, 8, 8, TransferFunction::Gamma(-1.)).is_err());
+}
+
+#[test]
+fn color_space() {
+    let mut liq = Attributes::new();
+    let px = [RGBA::new(255, 0, 0, 255), RGBA::new(128, 128, 128, 255), RGBA::new(234, 51, 35, 255), RGBA::new(0, 0, 0, 255)];
+    let mut quantize = |input, output| {
+        let mut img = Image::new(&liq, &px, 2, 2, TransferFunction::Srgb).unwrap();
+        img.set_color_space(input, output).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        let (pal, idx) = res.remapped(&mut img).unwrap();
+        idx.iter().map(|&i| pal[i as usize]).collect::<Vec<_>>()
+    };
+    assert_eq!(px[..], quantize(ColorSpace::DisplayP3, ColorSpace::DisplayP3)[..]);
+
+    let p3 = quantize(ColorSpace::Srgb, ColorSpace::DisplayP3);
+    let close = |a: RGBA, b: RGBA| a.r.abs_diff(b.r) <= 1 && a.g.abs_diff(b.g) <= 1 && a.b.abs_diff(b.b) <= 1 && a.a == b.a;
+    assert!(close(p3[0], px[2]), "{:?}", p3);
+    assert!(close(p3[1], px[1]), "{:?}", p3);
+
+    // out of gamut is clipped
+    let srgb = quantize(ColorSpace::Rec2020, ColorSpace::Srgb);
+    assert_eq!(RGBA::new(255, 0, 0, 255), srgb[0]);
+    assert!(close(srgb[1], px[1]), "{:?}", srgb);
+}
+
+#[test]
+fn yuv() {
+    fn expand(liq: &mut Attributes, img: &mut Image) -> Vec<RGBA> {
+        let mut res = liq.quantize(img).unwrap();
+        let (pal, idx) = res.remapped(img).unwrap();
+        idx.iter().map(|&i| pal[i as usize]).collect()
+    }
+    let mut liq = Attributes::new();
+
+    let y = [235, 235, 235, 235, 235, 235, 235, 235, 16, 16, 16, 16, 16, 16, 16, 16];
+    let (u, v) = ([128; 4], [128; 4]);
+    let mut img = Image::new_yuv(&liq, YuvPlanes::planar(&y, 4, &u, &v, 2), 4, 4, YuvFormat::default(), 0.).unwrap();
+    let px = expand(&mut liq, &mut img);
+    assert!(px[..8].iter().all(|&c| c == RGBA::new(255, 255, 255, 255)), "{:?}", px);
+    assert!(px[8..].iter().all(|&c| c == RGBA::new(0, 0, 0, 255)), "{:?}", px);
+
+    let y = [128; 6];
+    let uv = [128, 255, 128, 0];
+    let format = YuvFormat { subsampling: ChromaSubsampling::Yuv420, matrix: YuvMatrix::Bt709, range: YuvRange::Full };
+    let mut img = Image::new_yuv(&liq, YuvPlanes::semi_planar(&y, 3, &uv, 4), 3, 2, format, 0.).unwrap();
+    let px = expand(&mut liq, &mut img);
+    assert_eq!(RGBA::new(255, 69, 128, 255), px[0]);
+    assert_eq!(px[0], px[4]);
+    assert_eq!(RGBA::new(0, 188, 128, 255), px[2]);
+
+    let format = YuvFormat { subsampling: ChromaSubsampling::Yuv444, ..format };
+    assert!(Image::new_yuv(&liq, YuvPlanes::semi_planar(&y, 3, &uv, 4), 3, 2, format, 0.).is_err());
+    assert!(Image::new_yuv(&liq, YuvPlanes::planar(&y, 3, &u, &v, 2), 3, 3, YuvFormat::default(), 0.).is_err());
+}
+
+#[test]
+fn sub_image() {
+    let mut liq = Attributes::new();
+    let px: Vec<_> = (0..32 * 16u32).map(|i| {
+        let (x, y) = (i % 32, i / 32);
+        if x < 16 { RGBA::new(200 + (y as u8), x as u8 * 8, 0, 255) } else { RGBA::new(0, y as u8 * 8, 200 + (x as u8), 255) }
+    }).collect();
+    let rect = Rect { left: 4, top: 2, width: 10, height: 12 };
+
+    let mut standalone = liq.new_image_stride_borrow(&px[4 + 2 * 32..], 10, 12, 32, 0.).unwrap();
+    let expected = liq.quantize(&mut standalone).unwrap().remapped(&mut standalone).unwrap();
+
+    let mut img = liq.new_image(&px, 32, 16, 0.).unwrap();
+    let mut view = img.sub_image(rect).unwrap();
+    let mut res = liq.quantize(&mut view).unwrap();
+    assert_eq!(expected, res.remapped(&mut view).unwrap());
+    drop(view);
+
+    // view of a view reads rows of the outermost image
+    {
+        let outer = img.sub_image(Rect { left: 2, top: 1, width: 20, height: 14 }).unwrap();
+        let mut nested = outer.sub_image(Rect { left: 2, top: 1, width: 10, height: 12 }).unwrap();
+        assert_eq!(expected, liq.quantize(&mut nested).unwrap().remapped(&mut nested).unwrap());
+    }
+
+    liq.quantize(&mut img).unwrap();
+    assert!(img.sub_image(Rect { left: 30, top: 0, width: 3, height: 1 }).is_err());
+    assert!(matches!(img.sub_image(Rect { left: 1, top: usize::MAX, width: 3, height: 2 }), Err(Error::ValueOutOfRange { name: "rect bottom edge", .. })));
+    assert!(matches!(img.sub_image(Rect { left: usize::MAX, top: 0, width: 1, height: 1 }), Err(Error::ValueOutOfRange { name: "rect right edge", .. })));
+    let mut view = img.sub_image(rect).unwrap();
+    assert!(liq.quantize(&mut view).is_err());
+    let (pal, idx) = res.remapped(&mut view).unwrap();
+    for (i, &idx) in idx.iter().enumerate() {
+        let (orig, q) = (px[(i / 10 + 2) * 32 + i % 10 + 4], pal[idx as usize]);
+        assert!(orig.r.abs_diff(q.r) < 16 && orig.g.abs_diff(q.g) < 16 && q.b == 0, "{:?} {:?}", orig, q);
+    }
+}
+
+#[test]
+fn grayscale() {
+    let mut liq = Attributes::new();
+    let gray: Vec<u8> = (0..64u8).map(|i| i * 4).collect();
+    let mut img = Image::new_gray8(&liq, &gray, 8, 8, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let (pal, idx) = res.remapped(&mut img).unwrap();
+    for (&y, i) in gray.iter().zip(idx) {
+        assert_eq!(RGBA::new(y, y, y, 255), pal[i as usize]);
+    }
+    assert!(Image::new_gray8(&liq, &gray, 9, 8, 0.).is_err());
+
+    let gray_alpha: Vec<_> = (0..64u8).map(|i| GrayAlpha8::new(i * 4, if i < 32 { 255 } else { 128 })).collect();
+    let mut img = Image::new_gray_alpha8(&liq, &gray_alpha, 8, 8, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let (pal, idx) = res.remapped(&mut img).unwrap();
+    for (px, i) in gray_alpha.iter().zip(idx) {
+        let q = pal[i as usize];
+        assert!(q.r == q.g && q.g == q.b && px.v.abs_diff(q.g) <= 1 && px.a.abs_diff(q.a) <= 1, "{:?} {:?}", px, q);
+    }
+
+    // the transparent color is gray only in grayscale mode
+    let transparent_color = |liq: &mut Attributes| {
+        let px = [GrayAlpha8::new(0, 0), GrayAlpha8::new(100, 255)];
+        let mut img = Image::new_gray_alpha8(liq, &px, 2, 1, 0.).unwrap();
+        let (pal, idx) = liq.quantize(&mut img).unwrap().remapped(&mut img).unwrap();
+        pal[idx[0] as usize]
+    };
+    assert_eq!(RGBA::new(71, 112, 76, 0), transparent_color(&mut liq));
+
+    liq.set_grayscale(true);
+    assert_eq!(RGBA::new(112, 112, 112, 0), transparent_color(&mut liq));
+    liq.set_max_colors(8).unwrap();
+    let color: Vec<_> = (0..256u32).map(|i| RGBA::new(i as u8, (i * 7) as u8, 255 - i as u8, 255)).collect();
+    let mut img = liq.new_image(&color, 16, 16, 0.).unwrap();
+    img.add_fixed_color(RGBA::new(255, 0, 0, 255)).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.).unwrap();
+    let (pal, _) = res.remapped(&mut img).unwrap();
+    assert!(pal.len() > 1 && pal.len() <= 8);
+    assert!(pal.iter().all(|c| c.r == c.g && c.g == c.b), "{:?}", pal);
+    assert!(pal.contains(&RGBA::new(54, 54, 54, 255)));
+
+    // median cut and K-means work on luma and alpha only
+    let mut img = Image::new_gray_alpha8(&liq, &gray_alpha, 8, 8, 0.).unwrap();
+    let res = liq.quantize(&mut img).unwrap();
+    assert!(res.palette.as_slice().iter().all(|c| *c == pal::f_pixel::neutral(c.g, c.a)));
+}
+
+#[test]
+fn indexed() {
+    let mut liq = Attributes::new();
+    liq.set_max_colors(16).unwrap();
+    let palette: Vec<_> = (0..200u32).map(|i| RGBA::new(i as u8, (i * 3) as u8, (i * 7) as u8, if i < 180 { 255 } else { 100 })).collect();
+    let indices: Vec<u8> = (0..32 * 32u32).map(|i| ((i * i / 7 + i / 32) % 200) as u8).collect();
+    let rgba: Vec<_> = indices.iter().map(|&i| palette[i as usize]).collect();
+
+    let mut img = liq.new_image(&rgba, 32, 32, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let expected = res.remapped(&mut img).unwrap();
+
+    let mut img = Image::new_indexed(&liq, &palette, &indices, 32, 32, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    assert_eq!(expected, res.remapped(&mut img).unwrap());
+
+    assert!(Image::new_indexed(&liq, &palette, &indices, 32, 33, 0.).is_err());
+    assert!(Image::new_indexed(&liq, &[RGBA::new(0, 0, 0, 255); 257], &indices, 32, 32, 0.).is_err());
+
+    let mut img = Image::new_indexed(&liq, &palette[..1], &[0, 0, 1, 1], 2, 2, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let (pal, idx) = res.remapped(&mut img).unwrap();
+    assert_eq!(palette[0], pal[idx[0] as usize]);
+    assert_eq!(0, pal[idx[3] as usize].a);
+}
+
+#[test]
+fn indexed_count_overflow() {
+    let mut liq = Attributes::new();
+    liq.set_speed(10).unwrap();
+    // more than u32::MAX / 255 pixels of one index
+    let (width, height) = (4200, 4100);
+    let mut indices = vec![0u8; width * height];
+    indices[0] = 1;
+    let palette = [RGBA::new(10, 20, 30, 255), RGBA::new(200, 100, 0, 255)];
+    let mut img = Image::new_indexed(&liq, &palette, &indices, width, height, 0.).unwrap();
+    let mut hist = Histogram::new(&liq);
+    hist.add_image(&liq, &mut img).unwrap();
+    let hist = hist.finalize_builder(0.45455.into(), 0.);
+    let weights: Vec<f32> = hist.items.iter().map(|item| item.perceptual_weight).collect();
+    assert_eq!(2, weights.len());
+    assert!(weights.iter().any(|&w| w == 0.1 * (width * height) as f32), "{:?}", weights);
+}
+
 #[test]
 fn histogram() {
     let attr = Attributes::new();
@@ -103,11 +433,11 @@ fn poke_it() {
 
     // Configure the library
     let mut liq = Attributes::new();
-    liq.set_speed(5);
-    liq.set_quality(70, 99);
-    liq.set_min_posterization(1);
+    liq.set_speed(5).unwrap();
+    liq.set_quality(70, 99).unwrap();
+    liq.set_min_posterization(1).unwrap();
     assert_eq!(1, liq.min_posterization());
-    liq.set_min_posterization(0);
+    liq.set_min_posterization(0).unwrap();
 
     use std::sync::atomic::AtomicBool;
     use std::sync::atomic::Ordering::SeqCst;
@@ -136,7 +466,7 @@ fn poke_it() {
     };
 
     // Enable dithering for subsequent remappings
-    res.set_dithering_level(1.0);
+    res.set_dithering_level(1.0).unwrap();
 
     // You can reuse the result to generate several images with the same palette
     let (palette, pixels) = res.remapped(img).unwrap();
@@ -229,6 +559,331 @@ fn r_callback_test() {
     assert_eq!(123, res.palette().len());
 }
 
+#[test]
+fn packed() {
+    let mut liq = new();
+    let colors = [RGBA::new(0, 0, 0, 255), RGBA::new(255, 0, 0, 255), RGBA::new(0, 0, 255, 255)];
+    let pixels: Vec<RGBA> = (0..5 * 2).map(|i| colors[i % 3]).collect();
+    let mut img = liq.new_image(&pixels[..], 5, 2, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let (palette, indices) = res.remapped(&mut img).unwrap();
+    assert_eq!(3, palette.len());
+
+    let (palette, packed, format) = res.remapped_packed(&mut img, PackOptions { row_alignment: 4, ..Default::default() }).unwrap();
+    assert_eq!(PackedFormat { bits_per_pixel: 2, stride: 4 }, format);
+    assert_eq!(8, packed.len());
+    for (y, row) in packed.chunks(4).enumerate() {
+        for x in 0..5 {
+            let idx = (row[x / 4] >> (6 - 2 * (x % 4))) & 3;
+            assert_eq!(indices[y * 5 + x], idx);
+            assert_eq!(pixels[y * 5 + x], palette[idx as usize]);
+        }
+        assert_eq!(&[0, 0], &row[2..]);
+    }
+
+    let mut buf = [0xAA; 10];
+    let options = PackOptions { bits_per_pixel: Some(4), bit_order: BitOrder::LsbFirst, row_alignment: 1 };
+    // the size is checked before anything is remapped
+    res.set_event_callback(|e| assert!(!matches!(e, Event::Remap { .. })));
+    assert_eq!(Err(Error::BufferTooSmall { expected: 6, actual: 5 }), res.remap_into_packed(&mut img, options, &mut buf[..5]));
+    res.set_event_callback(|_| {});
+    assert_eq!(PackedFormat { bits_per_pixel: 4, stride: 3 }, res.remap_into_packed(&mut img, options, &mut buf).unwrap());
+    assert_eq!(indices[0] | indices[1] << 4, buf[0]);
+    assert_eq!(indices[4], buf[2]);
+    assert_eq!(indices[5] | indices[6] << 4, buf[3]);
+    assert_eq!(0xAA, buf[6]);
+
+    let options = PackOptions { bits_per_pixel: Some(1), ..Default::default() };
+    assert!(matches!(res.remapped_packed(&mut img, options), Err(Error::ValueOutOfRange { name: "palette length", .. })));
+}
+
+#[test]
+fn packed_dithered() {
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    let pixels: Vec<RGBA> = (0..64 * 64).map(|i| {
+        let (x, y) = ((i % 64) as u8, (i / 64) as u8);
+        if (x / 16 + y / 16) % 2 == 0 { RGBA::new(x * 4, y * 4, 128, 255) } else { RGBA::new(255 - y * 2, 40, x * 3, 255) }
+    }).collect();
+    // remapping refines the palette, so each remap starts from the same fresh result
+    let mut quantize = || {
+        let mut img = liq.new_image(&pixels[..], 64, 64, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        res.set_dithering_level(1.).unwrap();
+        (img, res)
+    };
+    let (mut img, mut res) = quantize();
+    let (palette, indices) = res.remapped(&mut img).unwrap();
+    assert!(res.stats().dither_map_time.is_some());
+
+    for bits_per_pixel in [4, 8] {
+        let options = PackOptions { bits_per_pixel: Some(bits_per_pixel), ..Default::default() };
+        let (mut img, mut res) = quantize();
+        let (packed_palette, packed, format) = res.remapped_packed(&mut img, options).unwrap();
+        assert_eq!(palette, packed_palette);
+        let (mut img, mut res) = quantize();
+        let mut buf = vec![0; format.stride * 64];
+        res.remap_into_packed(&mut img, options, &mut buf).unwrap();
+        assert_eq!(packed, buf);
+        let per_byte = 8 / bits_per_pixel as usize;
+        let unpacked: Vec<u8> = packed.chunks(format.stride).flat_map(|row| (0..64).map(move |x| {
+            (row[x / per_byte] >> (8 - bits_per_pixel as usize * (x % per_byte + 1))) & ((1u16 << bits_per_pixel) - 1) as u8
+        })).collect();
+        assert_eq!(indices, unpacked);
+    }
+}
+
+#[test]
+fn remapped_rgba() {
+    let mut liq = new();
+    liq.set_max_colors(8).unwrap();
+    let pixels: Vec<RGBA> = (0..64u8).map(|i| RGBA::new(i * 4, 128, 255 - i * 4, 255)).collect();
+    let mut remap = |expand: bool| {
+        let mut img = liq.new_image(&pixels[..], 8, 8, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        res.set_dithering_level(1.).unwrap();
+        if expand {
+            let (palette, indices) = res.remapped(&mut img).unwrap();
+            indices.iter().map(|&i| palette[i as usize]).collect::<Vec<_>>()
+        } else {
+            res.remapped_rgba(&mut img).unwrap()
+        }
+    };
+    assert_eq!(remap(true), remap(false));
+    let mut img = liq.new_image(&pixels[..], 8, 8, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    assert!(res.remap_into_rgba(&mut img, &mut [std::mem::MaybeUninit::uninit(); 63]).is_err());
+
+    let fg = [RGBA::new(0, 0, 0, 0), RGBA::new(255, 0, 0, 255)];
+    let bg = [RGBA::new(10, 200, 30, 255); 2];
+    let mut img = liq.new_image(&fg[..], 2, 1, 0.).unwrap();
+    img.set_background(liq.new_image(&bg[..], 2, 1, 0.).unwrap()).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    assert_eq!(vec![bg[0], fg[1]], res.remapped_rgba(&mut img).unwrap());
+}
+
+#[test]
+fn remap_to_sink() {
+    let mut liq = new();
+    liq.set_speed(10).unwrap();
+    liq.set_max_colors(8).unwrap();
+    let pixels: Vec<RGBA> = (0..50 * 40u32).map(|i| RGBA::new((i % 50 * 5) as u8, (i / 50 * 6) as u8, 90, 255)).collect();
+
+    for dither in [0., 1.] {
+        let mut remap = |stream: bool| {
+            let mut img = liq.new_image(&pixels[..], 50, 40, 0.).unwrap();
+            let mut res = liq.quantize(&mut img).unwrap();
+            res.set_dithering_level(dither).unwrap();
+            if !stream {
+                return res.remapped(&mut img).unwrap();
+            }
+            let mut indices = Vec::new();
+            res.remap_to_sink(&mut img, &mut |y: usize, row: &[u8]| {
+                assert_eq!(indices.len(), y * 50);
+                indices.extend_from_slice(row);
+            }).unwrap();
+            (res.palette_vec(), indices)
+        };
+        assert_eq!(remap(false), remap(true));
+    }
+
+    struct Abort(usize);
+    impl RemapSink for Abort {
+        fn palette(&mut self, palette: &[RGBA]) -> ControlFlow {
+            assert_eq!(8, palette.len());
+            ControlFlow::Continue
+        }
+        fn write_row(&mut self, _: usize, _: &[u8]) -> ControlFlow {
+            self.0 += 1;
+            if self.0 < 3 { ControlFlow::Continue } else { ControlFlow::Break }
+        }
+    }
+    let mut img = liq.new_image(&pixels[..], 50, 40, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let mut sink = Abort(0);
+    assert_eq!(Err(Error::Aborted { stage: Stage::Remapping }), res.remap_to_sink(&mut img, &mut sink));
+    assert_eq!(3, sink.0);
+}
+
+#[test]
+fn error_context() {
+    let mut liq = new();
+    assert_eq!(Err(Error::ValueOutOfRange { name: "speed", value: 11. }), liq.set_speed(11));
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, liq_error::from(liq.set_speed(0).unwrap_err()));
+
+    let pixels = [RGBA::new(0, 0, 0, 255); 5];
+    let err = Image::new(&liq, &pixels[..], 3, 2, 0.).err().unwrap();
+    assert_eq!(Error::BufferTooSmall { expected: 6, actual: 5 }, err);
+    assert_eq!("buffer too small: needs 6, got 5", err.to_string());
+
+    let pixels = [RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255), RGBA::new(255, 0, 0, 255), RGBA::new(0, 0, 255, 255)];
+    let mut img = Image::new(&liq, &pixels[..], 2, 2, 0.).unwrap();
+    liq.set_max_colors(2).unwrap();
+    liq.set_quality(100, 100).unwrap();
+    match liq.quantize(&mut img) {
+        Err(Error::QualityTooLow { quality, min_quality: 100 }) => assert!(quality < 100),
+        res => panic!("{:?}", res.map(|_| ())),
+    }
+
+    liq.set_quality(0, 100).unwrap();
+    liq.set_progress_callback(|_| ControlFlow::Break);
+    let err = liq.quantize(&mut img).unwrap_err();
+    assert_eq!(Error::Aborted { stage: Stage::Histogram }, err);
+    assert_eq!(liq_error::LIQ_ABORTED, err.into());
+}
+
+#[test]
+fn attributes_builder() {
+    assert_eq!(Some(Error::out_of_range("minimum quality", 90)), Attributes::builder().quality(90, 80).build().err());
+    assert_eq!(Some(Error::out_of_range("speed", 0)), Attributes::builder().speed(0).build().err());
+    let fixed = [RGBA::new(255, 0, 0, 255), RGBA::new(0, 255, 0, 255), RGBA::new(0, 0, 255, 255)];
+    assert_eq!(Some(Error::out_of_range("number of fixed colors", 3)), Attributes::builder().max_colors(2).fixed_colors(&fixed).build().err());
+    assert_eq!(Some(Error::out_of_range("max_histogram_entries", 10)), Attributes::builder().max_histogram_entries(10).build().err());
+
+    let mut liq = Attributes::builder()
+        .speed(10)
+        .quality(0, 90)
+        .max_colors(8)
+        .kmeans_iterations(3)
+        .feedback_loop_trials(2)
+        .fixed_colors(&fixed)
+        .build().unwrap();
+    assert_eq!((10, 8, (0, 90)), (liq.speed(), liq.max_colors(), liq.quality()));
+    assert_eq!(3, liq.kmeans_iterations(0, true).0);
+    assert_eq!(2, liq.feedback_loop_trials(0));
+
+    let pixels: Vec<_> = (0..64u8).map(|i| RGBA::new(i * 4, 128, 255 - i * 4, 255)).collect();
+    let mut img = liq.new_image(&pixels, 8, 8, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let palette = res.palette();
+    assert!(palette.len() <= 8);
+    assert!(fixed.iter().all(|c| palette.contains(c)), "{:?}", palette);
+}
+
+#[test]
+fn cancellation_token() {
+    let pixels: Vec<_> = (0..64 * 64).map(|i| RGBA::new(i as u8, (i >> 4) as u8, (i >> 8) as u8, 255)).collect();
+    let token = CancellationToken::new();
+    let mut liq = new();
+    liq.set_speed(1).unwrap();
+    liq.set_cancellation_token(token.clone());
+
+    let mut img = liq.new_image(&pixels, 64, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.).unwrap();
+    token.cancel();
+    assert_eq!(Some(Error::Aborted { stage: Stage::Remapping }), res.remapped(&mut img).err());
+    res.set_dithering_level(0.).unwrap();
+    assert_eq!(Some(Error::Aborted { stage: Stage::Remapping }), res.remapped(&mut img).err());
+    res.set_cancellation_token(CancellationToken::new());
+    assert!(res.remapped(&mut img).is_ok());
+
+    let mut img = liq.new_image(&pixels, 64, 64, 0.).unwrap();
+    assert_eq!(Some(Error::Aborted { stage: Stage::Histogram }), liq.quantize(&mut img).err());
+
+    // cancelled while the palette is being searched for
+    let token = CancellationToken::new();
+    liq.set_cancellation_token(token.clone());
+    let stage1 = liq.progress_stage1 as f32;
+    liq.set_progress_callback(move |percent| {
+        if percent > stage1 { token.cancel(); }
+        ControlFlow::Continue
+    });
+    let mut img = liq.new_image(&pixels, 64, 64, 0.).unwrap();
+    assert_eq!(Some(Error::Aborted { stage: Stage::Quantization }), liq.quantize(&mut img).err());
+}
+
+#[test]
+fn event_callback() {
+    use std::sync::{Arc, Mutex};
+    let pixels: Vec<_> = (0..64 * 64).map(|i| RGBA::new(i as u8, (i >> 4) as u8, (i >> 8) as u8, 255)).collect();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut liq = new();
+    let ev = events.clone();
+    liq.set_event_callback(move |e| ev.lock().unwrap().push(e.clone()));
+
+    let mut img = liq.new_image(&pixels, 64, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.).unwrap();
+    res.remapped(&mut img).unwrap();
+
+    let events = events.lock().unwrap();
+    assert!(matches!(events[0], Event::Histogram { colors, .. } if colors > 256));
+    assert!(matches!(events[1], Event::MedianCut { colors: 256, .. }));
+    assert!(events.iter().any(|e| matches!(e, Event::FeedbackTrial { trial: 1, .. })));
+    assert!(events.iter().any(|e| matches!(e, Event::KmeansIteration { iteration: 1, .. })));
+    assert!(events.iter().any(|e| matches!(e, Event::DitherMap { .. })));
+    assert!(matches!(events.last(), Some(Event::Remap { remapping_error: None, .. })));
+}
+
+#[test]
+fn quantization_stats() {
+    let pixels: Vec<_> = (0..64 * 64).map(|i| RGBA::new(i as u8, (i >> 4) as u8, (i >> 8) as u8, 255)).collect();
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    let mut img = liq.new_image(&pixels, 64, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+
+    let stats = res.stats();
+    assert!(stats.histogram_entries > 256);
+    assert!(stats.histogram_entries_before_posterization >= stats.histogram_entries);
+    assert!(!stats.trials.is_empty());
+    assert!(stats.trials.iter().all(|t| t.colors <= 16 && t.palette_error > 0.));
+    assert!(stats.trials_palette_error.is_some());
+    assert_eq!(stats.palette_error, res.quantization_error());
+    assert_eq!(stats.remap_time, None);
+
+    res.set_dithering_level(0.).unwrap();
+    res.remapped(&mut img).unwrap();
+    let stats = res.stats();
+    assert!(stats.remap_time.is_some());
+    assert_eq!(stats.dither_map_time, None);
+    assert_eq!(stats.remapping_error, res.remapping_error());
+}
+
+#[test]
+#[cfg(feature = "threads")]
+fn thread_pool() {
+    let pixels: Vec<_> = (0..64 * 64).map(|i| RGBA::new(i as u8, (i >> 4) as u8, (i >> 8) as u8, 255)).collect();
+    let remap = |liq: &mut Attributes| {
+        let mut img = liq.new_image(&pixels, 64, 64, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        res.remapped(&mut img).unwrap()
+    };
+
+    let mut liq = new();
+    assert!(matches!(liq.set_max_threads(0), Err(Error::ValueOutOfRange { .. })));
+    let expected = remap(&mut liq);
+
+    liq.set_max_threads(1).unwrap();
+    assert_eq!(expected, remap(&mut liq));
+    // no pool, so the work runs on the caller's thread
+    let caller = std::thread::current().id();
+    assert_eq!(4., liq.threads.sum(0..4, |_| if caller == std::thread::current().id() { 1. } else { 0. }));
+
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+    liq.set_thread_pool(std::sync::Arc::new(pool));
+    assert_eq!(expected, remap(&mut liq));
+    assert_eq!(0., liq.threads.sum(0..4, |_| if caller == std::thread::current().id() { 1. } else { 0. }));
+}
+
+#[test]
+#[cfg(feature = "threads")]
+fn remap_to_sink_fewer_rows_than_threads() {
+    let pixels: Vec<_> = (0..4 * 2).map(|i| RGBA::new(i * 30, 0, 255 - i * 30, 255)).collect();
+    let mut liq = new();
+    liq.set_max_threads(8).unwrap();
+    let mut img = liq.new_image(&pixels, 4, 2, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(0.).unwrap();
+    let mut rows = 0;
+    res.remap_to_sink(&mut img, &mut |_: usize, row: &[u8]| {
+        assert_eq!(4, row.len());
+        rows += 1;
+    }).unwrap();
+    assert_eq!(2, rows);
+}
+
 #[test]
 fn sizes() {
     use pal::PalF;
@@ -261,15 +916,15 @@ pub fn _unstable_internal_kmeans_bench() -> impl FnMut() {
     }).collect::<Vec<_>>();
 
     h.add_colors(&e, 0.).unwrap();
-    let mut hist = h.finalize_builder(0.45455, 0.);
+    let mut hist = h.finalize_builder(0.45455.into(), 0.);
 
-    let lut = pal::gamma_lut(0.45455);
+    let lut = pal::gamma_lut(0.45455.into());
     let mut p = PalF::new();
     for i in 0..=255 {
         p.push(pal::f_pixel::from_rgba(&lut, RGBA::new(i|7, i, i, 255)), PalPop::new(1.));
     }
 
     move || {
-        kmeans::Kmeans::iteration(&mut hist, &mut p, false);
+        kmeans::Kmeans::iteration(&mut hist, &mut p, false, &cancel::CancellationToken::new(), &threads::Threads::default());
     }
 }
@@ -1,3 +1,4 @@
+use crate::cancel::CancellationToken;
 use crate::hist::{HistItem, HistogramInternal};
 use crate::pal::{f_pixel, PalF, PalPop};
 use crate::pal::{PalLen, ARGBF};
@@ -24,31 +25,33 @@ struct MBox<'hist> {
     pub total_error: Option<f64>,
     /// max color difference between avg_color and any histogram entry
     pub max_error: f32,
+    /// Colors are gray, and vary only in luma and alpha
+    pub grayscale: bool,
 }
 
 impl<'hist> MBox<'hist> {
-    pub fn new(hist: &'hist mut [HistItem]) -> Self {
+    pub fn new(hist: &'hist mut [HistItem], grayscale: bool) -> Self {
         let weight_sum = hist.iter().map(|a| {
             debug_assert!(a.adjusted_weight.is_finite());
             debug_assert!(a.adjusted_weight > 0.);
             a.adjusted_weight as f64
         }).sum();
-        Self::new_c(hist, weight_sum, weighed_average_color(hist))
+        Self::new_c(hist, weight_sum, weighed_average_color(hist, grayscale), grayscale)
     }
 
-    fn new_s(hist: &'hist mut [HistItem], adjusted_weight_sum: f64, other_boxes: &[MBox<'_>]) -> Self {
+    fn new_s(hist: &'hist mut [HistItem], adjusted_weight_sum: f64, other_boxes: &[MBox<'_>], grayscale: bool) -> Self {
         debug_assert!(!hist.is_empty());
-        let mut avg_color = weighed_average_color(hist);
+        let mut avg_color = weighed_average_color(hist, grayscale);
         // It's possible that an average color will end up being bad for every entry,
         // so prefer picking actual colors so that at least one histogram entry will be satisfied.
         if (hist.len() < 500 && hist.len() > 2) || Self::is_useless_color(&avg_color, hist, other_boxes) {
             avg_color = hist.iter().min_by_key(|a| OrdFloat::<f32>::unchecked_new(avg_color.diff(&a.color))).map(|a| a.color).unwrap_or_default();
         }
-        Self::new_c(hist, adjusted_weight_sum, avg_color)
+        Self::new_c(hist, adjusted_weight_sum, avg_color, grayscale)
     }
 
-    fn new_c(hist: &'hist mut [HistItem], adjusted_weight_sum: f64, avg_color: f_pixel) -> Self {
-        let (variance, max_error) = Self::box_stats(hist, &avg_color);
+    fn new_c(hist: &'hist mut [HistItem], adjusted_weight_sum: f64, avg_color: f_pixel, grayscale: bool) -> Self {
+        let (variance, max_error) = Self::box_stats(hist, &avg_color, grayscale);
         Self {
             variance,
             max_error,
@@ -56,6 +59,7 @@ impl<'hist> MBox<'hist> {
             colors: hist,
             adjusted_weight_sum,
             total_error: None,
+            grayscale,
         }
     }
 
@@ -70,11 +74,17 @@ impl<'hist> MBox<'hist> {
         })
     }
 
-    fn box_stats(hist: &[HistItem], avg_color: &f_pixel) -> (ARGBF, f32) {
+    fn box_stats(hist: &[HistItem], avg_color: &f_pixel, grayscale: bool) -> (ARGBF, f32) {
         let mut variance = ARGBF::default();
         let mut max_error = 0.;
         for a in hist.iter() {
-            variance += (avg_color.0 - a.color.0).map(|c| c * c) * a.adjusted_weight;
+            if grayscale {
+                let (g, alpha) = (avg_color.g - a.color.g, avg_color.a - a.color.a);
+                variance.g += g * g * a.adjusted_weight;
+                variance.a += alpha * alpha * a.adjusted_weight;
+            } else {
+                variance += (avg_color.0 - a.color.0).map(|c| c * c) * a.adjusted_weight;
+            }
             let diff = avg_color.diff(&a.color);
             if diff > max_error {
                 max_error = diff;
@@ -91,6 +101,16 @@ impl<'hist> MBox<'hist> {
     }
 
     pub fn prepare_sort(&mut self) {
+        if self.grayscale {
+            // sorted by luma or alpha, whichever varies more, and then by the other
+            let luma_first = self.variance.g >= self.variance.a;
+            for a in self.colors.iter_mut() {
+                let (first, second) = if luma_first { (a.color.g, a.color.a) } else { (a.color.a, a.color.g) };
+                a.tmp.mc_sort_value = (((first * 65535.) as u32) << 16) | (second * 65535.) as u32;
+            }
+            return;
+        }
+
         struct ChanVariance {
             pub chan: usize,
             pub variance: f32,
@@ -143,8 +163,9 @@ impl<'hist> MBox<'hist> {
         let left_sum = left.iter().map(|a| a.adjusted_weight as f64).sum();
         let right_sum = self.adjusted_weight_sum - left_sum;
 
-        [MBox::new_s(left, left_sum, other_boxes),
-         MBox::new_s(right, right_sum, other_boxes)]
+        let grayscale = self.grayscale;
+        [MBox::new_s(left, left_sum, other_boxes, grayscale),
+         MBox::new_s(right, right_sum, other_boxes, grayscale)]
     }
 }
 
@@ -223,6 +244,7 @@ impl<'hist> MedianCutter<'hist> {
 
     pub fn new(hist: &'hist mut HistogramInternal, target_colors: PalLen) -> Self {
         let hist_total_perceptual_weight = hist.total_perceptual_weight;
+        let grayscale = hist.grayscale;
 
         debug_assert!(hist.clusters[0].begin == 0);
         debug_assert!(hist.clusters.last().unwrap().end as usize == hist.items.len());
@@ -241,10 +263,10 @@ impl<'hist> MedianCutter<'hist> {
                 prev_end = end;
                 let (this_box, rest) = hist_items.split_at_mut(end - begin);
                 hist_items = rest;
-                boxes.push(MBox::new(this_box));
+                boxes.push(MBox::new(this_box, grayscale));
             }
         } else {
-            boxes.push(MBox::new(hist_items));
+            boxes.push(MBox::new(hist_items, grayscale));
         };
 
         Self {
@@ -267,10 +289,10 @@ impl<'hist> MedianCutter<'hist> {
         palette
     }
 
-    fn cut(mut self, target_mse: f64, max_mse: f64) -> PalF {
+    fn cut(mut self, target_mse: f64, max_mse: f64, cancel: &CancellationToken) -> PalF {
         let max_mse = max_mse.max(quality_to_mse(20));
 
-        while self.boxes.len() < self.target_colors as usize {
+        while self.boxes.len() < self.target_colors as usize && !cancel.is_cancelled() {
             // first splits boxes that exceed quality limit (to have colors for things like odd green pixel),
             // later raises the limit to allow large smooth areas/gradients get colors.
             let fraction_done = self.boxes.len() as f64 / (self.target_colors as f64);
@@ -306,12 +328,26 @@ impl<'hist> MedianCutter<'hist> {
     }
 }
 
-pub(crate) fn mediancut(hist: &mut HistogramInternal, target_colors: PalLen, target_mse: f64, max_mse_per_color: f64) -> PalF {
-    MedianCutter::new(hist, target_colors).cut(target_mse, max_mse_per_color)
+/// If cancelled, it stops early with fewer colors
+pub(crate) fn mediancut(hist: &mut HistogramInternal, target_colors: PalLen, target_mse: f64, max_mse_per_color: f64, cancel: &CancellationToken) -> PalF {
+    MedianCutter::new(hist, target_colors).cut(target_mse, max_mse_per_color, cancel)
 }
 
-fn weighed_average_color(hist: &[HistItem]) -> f_pixel {
+fn weighed_average_color(hist: &[HistItem], grayscale: bool) -> f_pixel {
     debug_assert!(!hist.is_empty());
+    if grayscale {
+        let (mut g, mut a, mut sum) = (0., 0., 0.);
+        for c in hist {
+            sum += c.adjusted_weight;
+            g += c.color.g * c.adjusted_weight;
+            a += c.color.a * c.adjusted_weight;
+        }
+        if sum != 0. {
+            g /= sum;
+            a /= sum;
+        }
+        return f_pixel::neutral(g, a);
+    }
     let mut t = f_pixel::default();
     let mut sum = 0.;
     for c in hist {
@@ -5,11 +5,22 @@ use crate::pal::{f_pixel, PalF};
 impl<'pal> Nearest<'pal> {
     #[inline(never)]
     pub fn new(palette: &'pal PalF) -> Self {
-        let mut indexes: Vec<_> = (0..palette.len())
-            .map(|idx| MapIndex { idx: idx as _ })
-            .collect();
+        // Gray palettes are one-dimensional (plus alpha), so sorting by brightness is enough
+        let (root, by_luma) = if palette.as_slice().iter().all(|c| c.is_neutral()) {
+            let mut by_luma: Vec<_> = palette.as_slice().iter().enumerate()
+                .map(|(idx, c)| LumaIndex { g: c.g, idx: idx as _ })
+                .collect();
+            by_luma.sort_unstable_by_key(|i| OrdFloat::<f32>::unchecked_new(i.g));
+            (None, by_luma.into_boxed_slice())
+        } else {
+            let mut indexes: Vec<_> = (0..palette.len())
+                .map(|idx| MapIndex { idx: idx as _ })
+                .collect();
+            (Some(vp_create_node(&mut indexes, palette)), [].into())
+        };
         let mut handle = Nearest {
-            root: vp_create_node(&mut indexes, palette),
+            root,
+            by_luma,
             palette,
             nearest_other_color_dist: [0.; 256],
         };
@@ -18,7 +29,7 @@ impl<'pal> Nearest<'pal> {
                 idx: 0, distance: f32::MAX, distance_squared: f32::MAX,
                 exclude: i as i16,
             };
-            vp_search_node(&handle.root, color, &mut best);
+            handle.search_node(color, &mut best);
             handle.nearest_other_color_dist[i] = best.distance_squared / 4.;
         }
         handle
@@ -44,13 +55,23 @@ impl Nearest<'_> {
             Visitor { distance: f32::INFINITY, distance_squared: f32::INFINITY, idx: 0, exclude: -1, }
         };
 
-        vp_search_node(&self.root, px, &mut best_candidate);
+        self.search_node(px, &mut best_candidate);
         (best_candidate.idx as PalIndex, best_candidate.distance * best_candidate.distance)
     }
+
+    #[inline]
+    fn search_node(&self, px: &f_pixel, best_candidate: &mut Visitor) {
+        match &self.root {
+            Some(root) => vp_search_node(root, px, best_candidate),
+            None => luma_search(&self.by_luma, self.palette, px, best_candidate),
+        }
+    }
 }
 
 pub(crate) struct Nearest<'pal> {
-    root: Node,
+    /// `None` if the palette is gray, and `by_luma` is used instead
+    root: Option<Node>,
+    by_luma: Box<[LumaIndex]>,
     palette: &'pal PalF,
     nearest_other_color_dist: [f32; 256],
 }
@@ -59,6 +80,12 @@ pub struct MapIndex {
     pub idx: PalIndex,
 }
 
+/// Palette entry sorted by its (premultiplied, weighted) green, which for gray colors is the brightness
+pub struct LumaIndex {
+    pub g: f32,
+    pub idx: PalIndex,
+}
+
 pub struct Visitor {
     pub distance: f32,
     pub distance_squared: f32,
@@ -191,3 +218,27 @@ fn vp_search_node(mut node: &Node, needle: &f_pixel, best_candidate: &mut Visito
         break;
     }
 }
+
+/// Scans outwards from the closest brightness. Difference of green alone is a lower bound
+/// of the color difference, so the scan in each direction stops once it's larger than the best match.
+fn luma_search(by_luma: &[LumaIndex], palette: &PalF, needle: &f_pixel, best_candidate: &mut Visitor) {
+    let palette = palette.as_slice();
+    let start = by_luma.partition_point(|i| i.g < needle.g);
+
+    for i in by_luma[start..].iter() {
+        let dg = i.g - needle.g;
+        if dg * dg >= best_candidate.distance_squared {
+            break;
+        }
+        let distance_squared = palette[usize::from(i.idx)].diff(needle);
+        best_candidate.visit(distance_squared.sqrt(), distance_squared, i.idx);
+    }
+    for i in by_luma[..start].iter().rev() {
+        let dg = needle.g - i.g;
+        if dg * dg >= best_candidate.distance_squared {
+            break;
+        }
+        let distance_squared = palette[usize::from(i.idx)].diff(needle);
+        best_candidate.visit(distance_squared.sqrt(), distance_squared, i.idx);
+    }
+}
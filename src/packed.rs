@@ -0,0 +1,75 @@
+use crate::error::*;
+
+/// Order of pixels within a byte of packed indices
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BitOrder {
+    /// Leftmost pixel in the highest bits, as in PNG and BMP
+    MsbFirst,
+    /// Leftmost pixel in the lowest bits, used by some displays
+    LsbFirst,
+}
+
+/// How to pack palette indices into bytes. See [`QuantizationResult::remapped_packed`](crate::QuantizationResult::remapped_packed).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PackOptions {
+    /// 1, 2, 4 or 8. `None` picks the smallest depth that fits the final palette.
+    pub bits_per_pixel: Option<u8>,
+    pub bit_order: BitOrder,
+    /// Rows are padded with zeros to a multiple of this many bytes, e.g. 4 for BMP. 1 for no padding.
+    pub row_alignment: usize,
+}
+
+impl Default for PackOptions {
+    /// Automatic bit depth, MSB first, no padding
+    fn default() -> Self {
+        Self {
+            bits_per_pixel: None,
+            bit_order: BitOrder::MsbFirst,
+            row_alignment: 1,
+        }
+    }
+}
+
+/// Layout of packed rows written by the remap functions
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PackedFormat {
+    pub bits_per_pixel: u8,
+    /// Bytes per row, including padding
+    pub stride: usize,
+}
+
+impl PackOptions {
+    /// Bit depth and stride for an image of this width and palette size
+    pub fn format(&self, width: usize, palette_len: usize) -> Result<PackedFormat, Error> {
+        let bits_per_pixel = match self.bits_per_pixel {
+            Some(bits @ (1 | 2 | 4 | 8)) => bits,
+            Some(bits) => return Err(Error::out_of_range("bits_per_pixel", bits)),
+            None => [1, 2, 4, 8].iter().copied().find(|&bits| palette_len <= 1 << bits).unwrap_or(8),
+        };
+        if palette_len > 1 << bits_per_pixel {
+            return Err(Error::out_of_range("palette length", palette_len as f64));
+        }
+        if self.row_alignment == 0 {
+            return Err(Error::out_of_range("row_alignment", 0));
+        }
+        let row_bytes = (width * bits_per_pixel as usize).div_ceil(8);
+        let stride = row_bytes.div_ceil(self.row_alignment) * self.row_alignment;
+        Ok(PackedFormat { bits_per_pixel, stride })
+    }
+}
+
+/// `out` must be a whole row, including padding
+pub(crate) fn pack_row(indices: &[u8], out: &mut [u8], bits_per_pixel: u8, bit_order: BitOrder) {
+    let per_byte = 8 / bits_per_pixel as usize;
+    let (packed, padding) = out.split_at_mut(indices.len().div_ceil(per_byte));
+    for (byte, chunk) in packed.iter_mut().zip(indices.chunks(per_byte)) {
+        *byte = chunk.iter().enumerate().fold(0, |acc, (i, &idx)| {
+            let shift = match bit_order {
+                BitOrder::MsbFirst => 8 - bits_per_pixel as usize * (i + 1),
+                BitOrder::LsbFirst => bits_per_pixel as usize * i,
+            };
+            acc | (idx << shift)
+        });
+    }
+    padding.fill(0);
+}
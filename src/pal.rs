@@ -1,11 +1,317 @@
+use crate::error::Error;
 use crate::hist::{FixedColorsSet, HashColor};
 use arrayvec::ArrayVec;
+use std::fmt;
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 use std::os::raw::c_uint;
 
-/// 8-bit RGBA in sRGB. This is the only color format *publicly* used by the library.
+/// 8-bit RGBA in sRGB. This is the main color format *publicly* used by the library.
 pub type RGBA = rgb::RGBA8;
 
+/// 16-bit RGBA, for high-precision input images
+pub type RGBA16 = rgb::RGBA16;
+
+/// Gray and alpha, for grayscale input images
+pub type GrayAlpha8 = rgb::alt::GrayAlpha<u8>;
+
+/// Linear-light floating-point RGBA, with straight (not premultiplied) alpha in 0..1 range
+pub type RGBAF = rgb::RGBA<f32>;
+
+/// Order of channels in 32-bit pixels that aren't laid out as [`RGBA`]
+///
+/// Used with [`Image::new_ordered`](crate::Image::new_ordered) for input and
+/// [`QuantizationResult::palette_ordered`](crate::QuantizationResult::palette_ordered) for output.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ComponentOrder {
+    /// The library's native layout
+    RGBA,
+    /// Windows DIBs, Cairo and most frame grabbers on little-endian machines
+    BGRA,
+    ARGB,
+    ABGR,
+}
+
+impl Default for ComponentOrder {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::RGBA
+    }
+}
+
+impl ComponentOrder {
+    /// Reads a pixel stored in this order
+    #[inline(always)]
+    #[must_use]
+    pub fn unpack(self, px: [u8; 4]) -> RGBA {
+        let [a, b, c, d] = px;
+        match self {
+            Self::RGBA => RGBA::new(a, b, c, d),
+            Self::BGRA => RGBA::new(c, b, a, d),
+            Self::ARGB => RGBA::new(b, c, d, a),
+            Self::ABGR => RGBA::new(d, c, b, a),
+        }
+    }
+
+    /// Stores a pixel in this order
+    #[inline(always)]
+    #[must_use]
+    pub fn pack(self, px: RGBA) -> [u8; 4] {
+        let RGBA { r, g, b, a } = px;
+        match self {
+            Self::RGBA => [r, g, b, a],
+            Self::BGRA => [b, g, r, a],
+            Self::ARGB => [a, r, g, b],
+            Self::ABGR => [a, b, g, r],
+        }
+    }
+}
+
+/// Luma (BT.709 weights applied to gamma-encoded values) as a neutral color
+#[inline(always)]
+pub(crate) fn gray(px: RGBA) -> RGBA {
+    let y = ((px.r as u16 * 54 + px.g as u16 * 183 + px.b as u16 * 19 + 128) >> 8) as u8;
+    RGBA::new(y, y, y, px.a)
+}
+
+/// 16-bit version of `gray`
+#[inline(always)]
+pub(crate) fn gray16(px: RGBA16) -> RGBA16 {
+    let y = ((px.r as u32 * 54 + px.g as u32 * 183 + px.b as u32 * 19 + 128) >> 8) as u16;
+    RGBA16::new(y, y, y, px.a)
+}
+
+/// How color channel values are encoded, i.e. the curve that maps them to linear light
+///
+/// Accepted wherever a gamma is, so `0.45455` is the same as `TransferFunction::Gamma(0.45455)`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum TransferFunction {
+    /// The exact sRGB curve, which has a linear segment near black
+    Srgb,
+    /// A pure power function with this encoding exponent, e.g. `0.45455` for 1/2.2. `0` means the default.
+    Gamma(f64),
+    /// No encoding
+    Linear,
+    /// ITU-R BT.709 (and BT.2020) camera curve
+    Bt709,
+    /// SMPTE ST 2084 perceptual quantizer. Linear `1.0` is 10000 nits.
+    Pq,
+}
+
+impl From<f64> for TransferFunction {
+    #[inline]
+    fn from(gamma: f64) -> Self {
+        Self::Gamma(gamma)
+    }
+}
+
+const PQ_M1: f64 = 2610. / 16384.;
+const PQ_M2: f64 = 2523. / 4096. * 128.;
+const PQ_C1: f64 = 3424. / 4096.;
+const PQ_C2: f64 = 2413. / 4096. * 32.;
+const PQ_C3: f64 = 2392. / 4096. * 32.;
+
+impl TransferFunction {
+    /// Encoded value (0..1) to linear light (0..1)
+    #[must_use]
+    pub fn decode(self, value: f64) -> f64 {
+        let value = value.clamp(0., 1.);
+        match self {
+            Self::Srgb => if value <= 0.04045 { value / 12.92 } else { ((value + 0.055) / 1.055).powf(2.4) },
+            Self::Gamma(gamma) => value.powf(1. / gamma),
+            Self::Linear => value,
+            Self::Bt709 => if value < 0.081 { value / 4.5 } else { ((value + 0.099) / 1.099).powf(1. / 0.45) },
+            Self::Pq => {
+                let p = value.powf(1. / PQ_M2);
+                ((p - PQ_C1).max(0.) / (PQ_C2 - PQ_C3 * p)).powf(1. / PQ_M1)
+            },
+        }
+    }
+
+    /// Linear light (0..1) to encoded value (0..1)
+    #[must_use]
+    pub fn encode(self, linear: f64) -> f64 {
+        let linear = linear.clamp(0., 1.);
+        match self {
+            Self::Srgb => if linear <= 0.0031308 { linear * 12.92 } else { 1.055 * linear.powf(1. / 2.4) - 0.055 },
+            Self::Gamma(gamma) => linear.powf(gamma),
+            Self::Linear => linear,
+            Self::Bt709 => if linear < 0.018 { linear * 4.5 } else { 1.099 * linear.powf(0.45) - 0.099 },
+            Self::Pq => {
+                let l = linear.powf(PQ_M1);
+                ((PQ_C1 + PQ_C2 * l) / (1. + PQ_C3 * l)).powf(PQ_M2)
+            },
+        }
+    }
+
+    /// Power gamma closest to the curve, for APIs that predate `TransferFunction`
+    #[must_use]
+    pub fn approx_gamma(self) -> f64 {
+        match self {
+            Self::Gamma(gamma) => gamma,
+            Self::Linear => 1.,
+            Self::Bt709 => 0.5,
+            Self::Srgb | Self::Pq => 0.45455,
+        }
+    }
+
+    /// `Gamma(0.)` is replaced with the default. Fails if the gamma is out of range.
+    pub(crate) fn normalized(self) -> Result<Self, Error> {
+        match self {
+            Self::Gamma(gamma) if gamma > 0. && gamma <= 1. => Ok(self),
+            Self::Gamma(gamma) => if gamma == 0. { Ok(Self::Gamma(0.45455)) } else { Err(Error::out_of_range("gamma", gamma)) },
+            other => Ok(other),
+        }
+    }
+
+    /// Encoded value to the library's internal gamma
+    #[inline]
+    pub(crate) fn to_internal(self, value: f32) -> f32 {
+        match self {
+            Self::Gamma(gamma) => value.powf((INTERNAL_GAMMA / gamma) as f32),
+            _ => self.decode(value.into()).powf(INTERNAL_GAMMA) as f32,
+        }
+    }
+
+    /// The library's internal gamma to encoded value
+    #[inline]
+    pub(crate) fn to_encoded(self, internal: f32) -> f32 {
+        match self {
+            Self::Gamma(gamma) => internal.powf((gamma / INTERNAL_GAMMA) as f32),
+            _ => self.encode(f64::from(internal).powf(1. / INTERNAL_GAMMA)) as f32,
+        }
+    }
+}
+
+/// RGB primaries of a color space. All of them have the D65 white point.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// Also BT.709
+    Srgb,
+    /// DCI-P3 primaries, as used by Apple displays
+    DisplayP3,
+    /// BT.2020 wide gamut
+    Rec2020,
+}
+
+impl Default for ColorSpace {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::Srgb
+    }
+}
+
+/// Converts linear RGB between color spaces
+pub(crate) type GamutMatrix = [[f32; 3]; 3];
+
+impl ColorSpace {
+    fn to_xyz(self) -> [[f64; 3]; 3] {
+        match self {
+            Self::Srgb => [
+                [0.4124564, 0.3575761, 0.1804375],
+                [0.2126729, 0.7151522, 0.0721750],
+                [0.0193339, 0.1191920, 0.9503041],
+            ],
+            Self::DisplayP3 => [
+                [0.4865709, 0.2656677, 0.1982173],
+                [0.2289746, 0.6917385, 0.0792869],
+                [0.0000000, 0.0451134, 1.0439444],
+            ],
+            Self::Rec2020 => [
+                [0.6369580, 0.1446169, 0.1688810],
+                [0.2627002, 0.6779981, 0.0593017],
+                [0.0000000, 0.0280727, 1.0609851],
+            ],
+        }
+    }
+
+    /// `None` if no conversion is needed
+    pub(crate) fn gamut_matrix(self, output: Self) -> Option<GamutMatrix> {
+        if self == output {
+            return None;
+        }
+        let m = mat3_mul(&mat3_inverse(&output.to_xyz()), &self.to_xyz());
+        Some(m.map(|row| row.map(|c| c as f32)))
+    }
+}
+
+fn mat3_mul(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut out = [[0.; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, c) in row.iter_mut().enumerate() {
+            *c = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+fn mat3_inverse(m: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let cof = |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let adj = [
+        [cof(1, 2, 1, 2), -cof(0, 2, 1, 2), cof(0, 1, 1, 2)],
+        [-cof(1, 2, 0, 2), cof(0, 2, 0, 2), -cof(0, 1, 0, 2)],
+        [cof(1, 2, 0, 1), -cof(0, 2, 0, 1), cof(0, 1, 0, 1)],
+    ];
+    let det = m[0][0] * adj[0][0] + m[0][1] * adj[1][0] + m[0][2] * adj[2][0];
+    adj.map(|row| row.map(|c| c / det))
+}
+
+/// How linear-light [`RGBAF`] pixels are brought into the range and gamma of the output palette
+#[derive(Clone)]
+pub enum LinearTransfer {
+    /// Clip values to 0..1 and make the palette for sRGB output
+    Srgb,
+    /// Clip values to 0..1 and make the palette for output with this gamma (e.g. `0.45455`)
+    Gamma(f64),
+    /// Map a linear color channel value (of any range) to a 0..1 value encoded with sRGB gamma.
+    ///
+    /// Use it for tone-mapping of HDR values. Alpha is not passed through it.
+    ToneMap(Arc<dyn Fn(f32) -> f32 + Send + Sync>),
+}
+
+impl LinearTransfer {
+    /// Encoding of the values the palette is made for
+    pub(crate) fn output_transfer(&self) -> TransferFunction {
+        match *self {
+            Self::Srgb => TransferFunction::Srgb,
+            Self::Gamma(gamma) if gamma > 0. => TransferFunction::Gamma(gamma),
+            _ => TransferFunction::Gamma(0.45455),
+        }
+    }
+
+    /// Color channel value in internal gamma
+    #[inline]
+    pub(crate) fn to_internal(&self, linear: f32) -> f32 {
+        match self {
+            Self::Srgb | Self::Gamma(_) => linear.clamp(0., 1.).powf(INTERNAL_GAMMA as f32),
+            Self::ToneMap(f) => f(linear).clamp(0., 1.).powf((INTERNAL_GAMMA / 0.45455) as f32),
+        }
+    }
+
+    /// Color channel value encoded with `output_transfer()`
+    #[inline]
+    pub(crate) fn to_output(&self, linear: f32) -> f32 {
+        match self {
+            Self::Srgb => TransferFunction::Srgb.encode(linear.into()) as f32,
+            Self::Gamma(_) => linear.clamp(0., 1.).powf(self.output_transfer().approx_gamma() as f32),
+            Self::ToneMap(f) => f(linear).clamp(0., 1.),
+        }
+    }
+}
+
+impl fmt::Debug for LinearTransfer {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Srgb => f.write_str("Srgb"),
+            Self::Gamma(g) => write!(f, "Gamma({})", g),
+            Self::ToneMap(_) => f.write_str("ToneMap(_)"),
+        }
+    }
+}
+
 #[allow(clippy::upper_case_acronyms)]
 pub type ARGBF = rgb::alt::ARGB<f32>;
 
@@ -77,7 +383,7 @@ impl f_pixel {
     }
 
     #[allow(clippy::wrong_self_convention)]
-    pub fn to_rgb(&self, gamma: f64) -> RGBA {
+    pub fn to_rgb(&self, transfer: TransferFunction) -> RGBA {
         if self.a < MIN_OPAQUE_A {
             return RGBA::new(0, 0, 0, 0);
         }
@@ -87,17 +393,88 @@ impl f_pixel {
         let b = (LIQ_WEIGHT_A / LIQ_WEIGHT_B) * self.b / self.a;
         let a = (256. / LIQ_WEIGHT_A) * self.a;
 
-        let gamma = (gamma / INTERNAL_GAMMA) as f32;
-
         // 256, because numbers are in range 1..255.9999… rounded down
         RGBA {
-            r: (r.powf(gamma) * 256.) as u8,
-            g: (g.powf(gamma) * 256.) as u8,
-            b: (b.powf(gamma) * 256.) as u8,
+            r: (transfer.to_encoded(r) * 256.) as u8,
+            g: (transfer.to_encoded(g) * 256.) as u8,
+            b: (transfer.to_encoded(b) * 256.) as u8,
             a: a as u8,
         }
     }
 
+    /// Like `from_rgba`, but with a 65536-entry LUT from `gamma_lut16`
+    pub fn from_rgba16(gamma_lut: &[f32], px: RGBA16) -> Self {
+        debug_assert_eq!(gamma_lut.len(), 1 << 16);
+        let a = px.a as f32 / 65535.;
+        Self(ARGBF {
+            a: a * LIQ_WEIGHT_A,
+            r: gamma_lut[px.r as usize] * LIQ_WEIGHT_R * a,
+            g: gamma_lut[px.g as usize] * LIQ_WEIGHT_G * a,
+            b: gamma_lut[px.b as usize] * LIQ_WEIGHT_B * a,
+        })
+    }
+
+    /// Same luma as `gray` would give for `to_output` values
+    pub fn from_linear_gray(transfer: &LinearTransfer, px: RGBAF) -> Self {
+        let y = transfer.to_output(px.r) * (54. / 256.) + transfer.to_output(px.g) * (183. / 256.) + transfer.to_output(px.b) * (19. / 256.);
+        let y = transfer.output_transfer().to_internal(y);
+        let a = px.a.clamp(0., 1.);
+        Self(ARGBF {
+            a: a * LIQ_WEIGHT_A,
+            r: y * LIQ_WEIGHT_R * a,
+            g: y * LIQ_WEIGHT_G * a,
+            b: y * LIQ_WEIGHT_B * a,
+        })
+    }
+
+    /// Converts color to other primaries. Colors outside of the output gamut are clipped.
+    pub(crate) fn convert_gamut(&self, m: &GamutMatrix) -> Self {
+        let a = self.a / LIQ_WEIGHT_A;
+        if a < 1. / 1024. {
+            return *self;
+        }
+        let linear = |c: f32, weight: f32| (c / (weight * a)).max(0.).powf((1. / INTERNAL_GAMMA) as f32);
+        let rgb = [linear(self.r, LIQ_WEIGHT_R), linear(self.g, LIQ_WEIGHT_G), linear(self.b, LIQ_WEIGHT_B)];
+        let out = |row: &[f32; 3], weight: f32| {
+            let c = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2];
+            c.clamp(0., 1.).powf(INTERNAL_GAMMA as f32) * weight * a
+        };
+        Self(ARGBF {
+            a: self.a,
+            r: out(&m[0], LIQ_WEIGHT_R),
+            g: out(&m[1], LIQ_WEIGHT_G),
+            b: out(&m[2], LIQ_WEIGHT_B),
+        })
+    }
+
+    /// Gray of this luma (the weighed green channel) and alpha
+    #[inline]
+    pub fn neutral(g: f32, a: f32) -> Self {
+        Self(ARGBF {
+            a,
+            r: g * (LIQ_WEIGHT_R / LIQ_WEIGHT_G),
+            g,
+            b: g * (LIQ_WEIGHT_B / LIQ_WEIGHT_G),
+        })
+    }
+
+    /// Has no hue. Neutral colors stay neutral through median cut and K-means averaging.
+    #[inline]
+    pub fn is_neutral(&self) -> bool {
+        let g = self.g / LIQ_WEIGHT_G;
+        (self.r / LIQ_WEIGHT_R - g).abs() < 1e-4 && (self.b / LIQ_WEIGHT_B - g).abs() < 1e-4
+    }
+
+    pub fn from_linear(transfer: &LinearTransfer, px: RGBAF) -> Self {
+        let a = px.a.clamp(0., 1.);
+        Self(ARGBF {
+            a: a * LIQ_WEIGHT_A,
+            r: transfer.to_internal(px.r) * LIQ_WEIGHT_R * a,
+            g: transfer.to_internal(px.g) * LIQ_WEIGHT_G * a,
+            b: transfer.to_internal(px.b) * LIQ_WEIGHT_B * a,
+        })
+    }
+
     pub fn from_rgba(gamma_lut: &[f32; 256], px: RGBA) -> Self {
         let a = px.a as f32 / 255.;
         Self(ARGBF {
@@ -245,15 +622,21 @@ impl PalF {
 }
 
 #[inline(always)]
-pub fn gamma_lut(gamma: f64) -> [f32; 256] {
-    debug_assert!(gamma > 0.);
+pub fn gamma_lut(transfer: TransferFunction) -> [f32; 256] {
+    debug_assert!(transfer.normalized() == Ok(transfer));
     let mut tmp = [0.; 256];
     for (i, t) in tmp.iter_mut().enumerate() {
-        *t = ((i as f32) / 255.).powf((INTERNAL_GAMMA / gamma) as f32);
+        *t = transfer.to_internal(i as f32 / 255.);
     }
     tmp
 }
 
+/// 16-bit version of `gamma_lut`. It's big, so create it once per image.
+pub fn gamma_lut16(transfer: TransferFunction) -> Box<[f32]> {
+    debug_assert!(transfer.normalized() == Ok(transfer));
+    (0..=u16::MAX).map(move |i| transfer.to_internal(i as f32 / 65535.)).collect()
+}
+
 #[repr(C)]
 pub struct Palette {
     pub count: c_uint,
@@ -0,0 +1,304 @@
+//! Reading and writing palettes in file formats of image editors
+
+use crate::pal::RGBA;
+use crate::quant::QuantizationResult;
+use std::fmt;
+use std::io;
+
+/// Palette file formats. See [`write_palette`] and [`read_palette`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PaletteFormat {
+    /// GIMP `.gpl`. No alpha.
+    Gpl,
+    /// Adobe Color Table `.act`. Up to 256 colors, and one transparent entry.
+    Act,
+    /// Adobe Color Swatch `.aco`, with the hex colors as swatch names. No alpha.
+    Aco,
+    /// JASC/Paint Shop Pro `.pal`. No alpha.
+    JascPal,
+    /// Paint.NET `.txt`, with alpha
+    PaintNet,
+    /// `#rrggbb` lines, or `#rrggbbaa` for colors that aren't opaque
+    Hex,
+}
+
+/// Writes colors in the given order. Alpha is kept only where the format supports it.
+///
+/// Entries with alpha below 128 count as transparent in formats that have only one transparent color.
+pub fn write_palette(palette: &[RGBA], format: PaletteFormat, mut writer: impl io::Write) -> io::Result<()> {
+    match format {
+        PaletteFormat::Gpl => {
+            writeln!(writer, "GIMP Palette\nName: imagequant\nColumns: 16\n#")?;
+            for (i, c) in palette.iter().enumerate() {
+                writeln!(writer, "{:3} {:3} {:3}\tIndex {}", c.r, c.g, c.b, i)?;
+            }
+        },
+        PaletteFormat::Act => {
+            if palette.len() > 256 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "ACT palettes have up to 256 colors"));
+            }
+            let mut table = [0u8; 768 + 4];
+            for (rgb, c) in table.chunks_exact_mut(3).zip(palette) {
+                rgb.copy_from_slice(&[c.r, c.g, c.b]);
+            }
+            let transparent = palette.iter().position(|c| c.a < 128).map_or(0xFFFF, |i| i as u16);
+            table[768..770].copy_from_slice(&(palette.len() as u16).to_be_bytes());
+            table[770..].copy_from_slice(&transparent.to_be_bytes());
+            writer.write_all(&table)?;
+        },
+        PaletteFormat::Aco => {
+            if palette.len() > 0xFFFF {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many colors"));
+            }
+            // Version 1 is for old readers, version 2 repeats it with names
+            for version in [1u16, 2] {
+                writer.write_all(&version.to_be_bytes())?;
+                writer.write_all(&(palette.len() as u16).to_be_bytes())?;
+                for c in palette {
+                    let mut entry = [0u8; 10];
+                    for (dst, ch) in entry[2..8].chunks_exact_mut(2).zip([c.r, c.g, c.b]) {
+                        dst.copy_from_slice(&(ch as u16 * 257).to_be_bytes());
+                    }
+                    writer.write_all(&entry)?;
+                    if version == 2 {
+                        let name = format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b);
+                        writer.write_all(&(name.len() as u32 + 1).to_be_bytes())?;
+                        for ch in name.encode_utf16().chain(std::iter::once(0)) {
+                            writer.write_all(&ch.to_be_bytes())?;
+                        }
+                    }
+                }
+            }
+        },
+        PaletteFormat::JascPal => {
+            write!(writer, "JASC-PAL\r\n0100\r\n{}\r\n", palette.len())?;
+            for c in palette {
+                write!(writer, "{} {} {}\r\n", c.r, c.g, c.b)?;
+            }
+        },
+        PaletteFormat::PaintNet => {
+            writeln!(writer, ";paint.net Palette File\n;Colors: {}", palette.len())?;
+            for c in palette {
+                writeln!(writer, "{:02X}{:02X}{:02X}{:02X}", c.a, c.r, c.g, c.b)?;
+            }
+        },
+        PaletteFormat::Hex => {
+            for c in palette {
+                if c.a == 255 {
+                    writeln!(writer, "#{:02x}{:02x}{:02x}", c.r, c.g, c.b)?;
+                } else {
+                    writeln!(writer, "#{:02x}{:02x}{:02x}{:02x}", c.r, c.g, c.b, c.a)?;
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+impl QuantizationResult {
+    /// Writes the final palette in a format for image editors. See [`write_palette`].
+    ///
+    /// Call it after remapping, because remapping changes the palette.
+    pub fn write_palette(&mut self, format: PaletteFormat, writer: impl io::Write) -> io::Result<()> {
+        write_palette(self.palette(), format, writer)
+    }
+}
+
+/// Why a palette file couldn't be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaletteParseError {
+    /// In a text format. Lines are counted from 1.
+    Line { line: usize, message: String },
+    /// In a binary format
+    Offset { offset: usize, message: String },
+    /// Only GPL, ACT, JASC and hex palettes can be read
+    UnsupportedFormat,
+}
+
+impl std::error::Error for PaletteParseError {}
+
+impl fmt::Display for PaletteParseError {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Line { line, message } => write!(f, "line {}: {}", line, message),
+            Self::Offset { offset, message } => write!(f, "byte {}: {}", offset, message),
+            Self::UnsupportedFormat => f.write_str("reading of this palette format is not supported"),
+        }
+    }
+}
+
+/// Parses a palette file. Colors are in the file's order.
+///
+/// The colors can be added with [`Image::add_fixed_color`](crate::Image::add_fixed_color) or
+/// [`Histogram::add_fixed_color`](crate::Histogram::add_fixed_color). To remap to exactly this palette,
+/// add all of them and set `max_colors` to their number.
+pub fn read_palette(data: &[u8], format: PaletteFormat) -> Result<Vec<RGBA>, PaletteParseError> {
+    match format {
+        PaletteFormat::Act => read_act(data),
+        PaletteFormat::Gpl => read_gpl(text(data)?),
+        PaletteFormat::JascPal => read_jasc(text(data)?),
+        PaletteFormat::Hex => read_hex(text(data)?),
+        PaletteFormat::Aco | PaletteFormat::PaintNet => Err(PaletteParseError::UnsupportedFormat),
+    }
+}
+
+fn text(data: &[u8]) -> Result<&str, PaletteParseError> {
+    std::str::from_utf8(data).map_err(|e| {
+        let line = 1 + data[..e.valid_up_to()].iter().filter(|&&b| b == b'\n').count();
+        PaletteParseError::Line { line, message: "not valid UTF-8".into() }
+    })
+}
+
+fn line_err(line: usize, message: impl Into<String>) -> PaletteParseError {
+    PaletteParseError::Line { line: line + 1, message: message.into() }
+}
+
+/// `r g b` decimal components, followed by anything
+fn parse_rgb(line_no: usize, line: &str) -> Result<RGBA, PaletteParseError> {
+    let mut parts = line.split_whitespace();
+    let mut next = || -> Result<u8, PaletteParseError> {
+        let part = parts.next().ok_or_else(|| line_err(line_no, "expected 3 color components"))?;
+        part.parse().map_err(|_| line_err(line_no, format!("'{}' is not a number 0-255", part)))
+    };
+    Ok(RGBA::new(next()?, next()?, next()?, 255))
+}
+
+fn read_gpl(text: &str) -> Result<Vec<RGBA>, PaletteParseError> {
+    let mut lines = text.lines().enumerate();
+    match lines.next() {
+        Some((_, header)) if header.trim_end() == "GIMP Palette" => {},
+        _ => return Err(line_err(0, "expected 'GIMP Palette'")),
+    }
+    let mut colors = Vec::new();
+    for (line_no, line) in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+            continue;
+        }
+        colors.push(parse_rgb(line_no, line)?);
+    }
+    Ok(colors)
+}
+
+fn read_jasc(text: &str) -> Result<Vec<RGBA>, PaletteParseError> {
+    let mut lines = text.lines().map(str::trim).enumerate();
+    if lines.next().map(|(_, l)| l) != Some("JASC-PAL") {
+        return Err(line_err(0, "expected 'JASC-PAL'"));
+    }
+    if lines.next().map(|(_, l)| l) != Some("0100") {
+        return Err(line_err(1, "expected version '0100'"));
+    }
+    let count = match lines.next() {
+        Some((_, count)) => count.parse::<usize>().map_err(|_| line_err(2, "expected number of colors"))?,
+        None => return Err(line_err(2, "expected number of colors")),
+    };
+    let colors = lines.filter(|(_, l)| !l.is_empty()).map(|(line_no, l)| parse_rgb(line_no, l)).collect::<Result<Vec<_>, _>>()?;
+    if colors.len() != count {
+        return Err(line_err(2, format!("the file has {} colors, not {}", colors.len(), count)));
+    }
+    Ok(colors)
+}
+
+fn read_hex(text: &str) -> Result<Vec<RGBA>, PaletteParseError> {
+    let mut colors = Vec::new();
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with("//") {
+            continue;
+        }
+        let hex = line.strip_prefix('#').unwrap_or(line);
+        let channel = |i: usize| hex.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok());
+        let color = match hex.len() {
+            6 => channel(0).zip(channel(2)).zip(channel(4)).map(|((r, g), b)| RGBA::new(r, g, b, 255)),
+            8 => channel(0).zip(channel(2)).zip(channel(4)).zip(channel(6)).map(|(((r, g), b), a)| RGBA::new(r, g, b, a)),
+            _ => None,
+        };
+        colors.push(color.ok_or_else(|| line_err(line_no, format!("'{}' is not a #rrggbb or #rrggbbaa color", line)))?);
+    }
+    Ok(colors)
+}
+
+fn read_act(data: &[u8]) -> Result<Vec<RGBA>, PaletteParseError> {
+    if data.len() != 768 && data.len() != 772 {
+        return Err(PaletteParseError::Offset { offset: data.len().min(768), message: "ACT files are 768 or 772 bytes long".into() });
+    }
+    let mut colors: Vec<RGBA> = data[..768].chunks_exact(3).map(|c| RGBA::new(c[0], c[1], c[2], 255)).collect();
+    if let Some(trailer) = data.get(768..772) {
+        let count = u16::from_be_bytes([trailer[0], trailer[1]]) as usize;
+        let transparent = u16::from_be_bytes([trailer[2], trailer[3]]) as usize;
+        if count > 256 {
+            return Err(PaletteParseError::Offset { offset: 768, message: format!("{} colors is more than 256", count) });
+        }
+        // 0 is sometimes used for "all 256"
+        if count > 0 {
+            colors.truncate(count);
+        }
+        if let Some(c) = colors.get_mut(transparent) {
+            c.a = 0;
+        }
+    }
+    Ok(colors)
+}
+
+#[test]
+fn palette_parsers() {
+    let palette = vec![RGBA::new(0, 0, 0, 0), RGBA::new(255, 128, 1, 255), RGBA::new(16, 32, 48, 255)];
+    let opaque: Vec<_> = palette.iter().map(|c| RGBA { a: 255, ..*c }).collect();
+    for (format, expected) in [(PaletteFormat::Act, &palette), (PaletteFormat::Hex, &palette), (PaletteFormat::Gpl, &opaque), (PaletteFormat::JascPal, &opaque)] {
+        let mut file = Vec::new();
+        write_palette(&palette, format, &mut file).unwrap();
+        assert_eq!(*expected, read_palette(&file, format).unwrap(), "{:?}", format);
+    }
+
+    assert_eq!(vec![RGBA::new(1, 2, 3, 255)], read_palette(b"; comment\n\n010203\n", PaletteFormat::Hex).unwrap());
+    assert_eq!(Err(PaletteParseError::Line { line: 3, message: "'#12345' is not a #rrggbb or #rrggbbaa color".into() }), read_palette(b"#123456\n\n#12345\n", PaletteFormat::Hex));
+    assert_eq!(Err(PaletteParseError::Line { line: 4, message: "'300' is not a number 0-255".into() }), read_palette(b"GIMP Palette\nName: x\n#\n1 2 300\n", PaletteFormat::Gpl));
+    assert!(matches!(read_palette(b"GIMP\n", PaletteFormat::Gpl), Err(PaletteParseError::Line { line: 1, .. })));
+    assert!(matches!(read_palette(b"JASC-PAL\r\n0100\r\n2\r\n1 2 3\r\n", PaletteFormat::JascPal), Err(PaletteParseError::Line { line: 3, .. })));
+    assert!(matches!(read_palette(&[0; 770], PaletteFormat::Act), Err(PaletteParseError::Offset { offset: 768, .. })));
+    assert_eq!(Err(PaletteParseError::UnsupportedFormat), read_palette(b"", PaletteFormat::Aco));
+
+    let mut liq = crate::new();
+    liq.set_max_colors(opaque.len() as u32).unwrap();
+    let pixels: Vec<RGBA> = (0..64).map(|i| RGBA::new(i * 4, 100, 50, 255)).collect();
+    let mut img = liq.new_image(&pixels[..], 8, 8, 0.).unwrap();
+    for &c in &opaque {
+        img.add_fixed_color(c).unwrap();
+    }
+    let mut res = liq.quantize(&mut img).unwrap();
+    let (mut remapped, _) = res.remapped(&mut img).unwrap();
+    remapped.sort_by_key(|c| (c.r, c.g, c.b));
+    let mut opaque = opaque;
+    opaque.sort_by_key(|c| (c.r, c.g, c.b));
+    assert_eq!(opaque, remapped);
+}
+
+#[test]
+fn palette_writers() {
+    let palette = [RGBA::new(0, 0, 0, 0), RGBA::new(255, 128, 1, 255), RGBA::new(16, 32, 48, 200)];
+    let write = |format| {
+        let mut out = Vec::new();
+        write_palette(&palette, format, &mut out).unwrap();
+        out
+    };
+
+    assert_eq!("GIMP Palette\nName: imagequant\nColumns: 16\n#\n  0   0   0\tIndex 0\n255 128   1\tIndex 1\n 16  32  48\tIndex 2\n", String::from_utf8(write(PaletteFormat::Gpl)).unwrap());
+    assert_eq!("JASC-PAL\r\n0100\r\n3\r\n0 0 0\r\n255 128 1\r\n16 32 48\r\n", String::from_utf8(write(PaletteFormat::JascPal)).unwrap());
+    assert_eq!(";paint.net Palette File\n;Colors: 3\n00000000\nFFFF8001\nC8102030\n", String::from_utf8(write(PaletteFormat::PaintNet)).unwrap());
+    assert_eq!("#00000000\n#ff8001\n#102030c8\n", String::from_utf8(write(PaletteFormat::Hex)).unwrap());
+
+    let act = write(PaletteFormat::Act);
+    assert_eq!(772, act.len());
+    assert_eq!(&[0, 0, 0, 255, 128, 1, 16, 32, 48, 0], &act[..10]);
+    assert_eq!(&[0, 3, 0, 0], &act[768..]);
+
+    let aco = write(PaletteFormat::Aco);
+    assert_eq!(&[0, 1, 0, 3], &aco[..4]);
+    assert_eq!(&[0, 0, 0xFF, 0xFF, 0x80, 0x80, 0x01, 0x01, 0, 0], &aco[14..24]);
+    let v2 = 4 + 3 * 10;
+    assert_eq!(&[0, 2, 0, 3], &aco[v2..v2 + 4]);
+    assert_eq!(v2 + 4 + 3 * (10 + 4 + 8 * 2), aco.len());
+
+    assert!(write_palette(&[RGBA::default(); 257], PaletteFormat::Act, io::sink()).is_err());
+}
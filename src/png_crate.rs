@@ -0,0 +1,152 @@
+//! Writing quantized images as palette PNG files, using the [`png`](https://lib.rs/png) crate.
+//!
+//! Requires the `png` cargo feature.
+
+use crate::error::*;
+use crate::image::Image;
+use crate::packed::PackOptions;
+use crate::pal::TransferFunction;
+use crate::quant::QuantizationResult;
+use png::{BitDepth, ColorType, Encoder, ScaledFloat, SrgbRenderingIntent};
+use std::io;
+
+/// Settings for [`QuantizationResult::write_png_with_options`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PngOptions {
+    /// Write sRGB and gAMA chunks matching [`QuantizationResult::output_gamma`]. On by default.
+    pub color_chunks: bool,
+}
+
+impl Default for PngOptions {
+    fn default() -> Self {
+        Self { color_chunks: true }
+    }
+}
+
+impl QuantizationResult {
+    /// Remap the image and write it as a palette PNG file.
+    ///
+    /// The image is remapped the same way as in [`QuantizationResult::remapped`], including the dither map.
+    /// The bit depth is the smallest that fits the palette, and the tRNS chunk omits trailing opaque entries.
+    ///
+    /// Errors from remapping are returned as `io::ErrorKind::Other` with the [`Error`] inside.
+    pub fn write_png(&mut self, image: &mut Image<'_, '_>, writer: impl io::Write) -> io::Result<()> {
+        self.write_png_with_options(image, writer, PngOptions::default())
+    }
+
+    /// Same as [`QuantizationResult::write_png`], with control over the optional chunks
+    pub fn write_png_with_options(&mut self, image: &mut Image<'_, '_>, writer: impl io::Write, options: PngOptions) -> io::Result<()> {
+        let (palette, packed, format) = self.remapped_packed(image, PackOptions::default()).map_err(to_io)?;
+
+        let mut encoder = Encoder::new(writer, image.width() as u32, image.height() as u32);
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(match format.bits_per_pixel {
+            1 => BitDepth::One,
+            2 => BitDepth::Two,
+            4 => BitDepth::Four,
+            _ => BitDepth::Eight,
+        });
+        encoder.set_palette(palette.iter().flat_map(|c| [c.r, c.g, c.b]).collect::<Vec<u8>>());
+
+        let num_transparent = palette.iter().rposition(|c| c.a < 255).map_or(0, |i| i + 1);
+        if num_transparent > 0 {
+            encoder.set_trns(palette[..num_transparent].iter().map(|c| c.a).collect::<Vec<u8>>());
+        }
+
+        if options.color_chunks {
+            match self.output_transfer() {
+                // with the fallback gAMA for decoders that don't support sRGB
+                TransferFunction::Srgb | TransferFunction::Gamma(0.45455) => {
+                    encoder.set_source_srgb(SrgbRenderingIntent::Perceptual);
+                    encoder.set_source_gamma(ScaledFloat::new(0.45455));
+                },
+                // gAMA can't describe the PQ curve
+                TransferFunction::Pq => {},
+                transfer => encoder.set_source_gamma(ScaledFloat::new(transfer.approx_gamma() as f32)),
+            }
+        }
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&packed)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+fn to_io(err: Error) -> io::Error {
+    io::Error::other(err)
+}
+
+#[test]
+fn png_bit_depth_and_trns() {
+    use crate::attr::Attributes;
+    use crate::pal::RGBA;
+
+    let mut liq = Attributes::new();
+    let pixels = [RGBA::new(0, 0, 0, 0), RGBA::new(255, 0, 0, 128), RGBA::new(0, 255, 0, 255), RGBA::new(0, 0, 255, 255), RGBA::new(9, 9, 9, 255)];
+    let mut img = Image::new(&liq, &pixels[..], 5, 1, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let mut file = Vec::new();
+    res.write_png(&mut img, &mut file).unwrap();
+
+    let mut reader = png::Decoder::new(&file[..]).read_info().unwrap();
+    let info = reader.info();
+    assert_eq!((ColorType::Indexed, BitDepth::Four), (info.color_type, info.bit_depth));
+    assert_eq!(Some(&[0, 128][..]), info.trns.as_deref());
+    assert!(info.srgb.is_some());
+    assert_eq!(Some(ScaledFloat::new(0.45455)), info.source_gamma);
+    let palette = info.palette.as_deref().unwrap().to_vec();
+
+    let mut buf = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buf).unwrap();
+    for (x, px) in pixels.iter().enumerate() {
+        let idx = ((buf[x / 2] >> (4 - 4 * (x % 2))) & 15) as usize;
+        if px.a > 0 {
+            assert_eq!(&[px.r, px.g, px.b], &palette[idx * 3..idx * 3 + 3]);
+        }
+    }
+
+    res.set_output_gamma(TransferFunction::Linear).unwrap();
+    let mut file = Vec::new();
+    res.write_png_with_options(&mut img, &mut file, PngOptions { color_chunks: true }).unwrap();
+    let reader = png::Decoder::new(&file[..]).read_info().unwrap();
+    assert_eq!(Some(ScaledFloat::new(1.)), reader.info().source_gamma);
+    assert!(reader.info().srgb.is_none());
+}
+
+#[test]
+fn png_same_as_remapped() {
+    use crate::attr::Attributes;
+    use crate::pal::RGBA;
+
+    let mut liq = Attributes::new();
+    liq.set_max_colors(16).unwrap();
+    let pixels: Vec<RGBA> = (0..48 * 48).map(|i| {
+        let (x, y) = ((i % 48) as u8, (i / 48) as u8);
+        if (x / 12 + y / 12) % 2 == 0 { RGBA::new(x * 5, y * 5, 128, 255) } else { RGBA::new(255 - y * 3, 40, x * 4, 255) }
+    }).collect();
+    // remapping refines the palette, so both remaps start from the same fresh result
+    let mut quantize = || {
+        let mut img = Image::new(&liq, &pixels[..], 48, 48, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        res.set_dithering_level(1.).unwrap();
+        (img, res)
+    };
+    let (mut img, mut res) = quantize();
+    let (palette, indices) = res.remapped(&mut img).unwrap();
+    assert!(res.stats().dither_map_time.is_some());
+
+    let (mut img, mut res) = quantize();
+    let mut file = Vec::new();
+    res.write_png(&mut img, &mut file).unwrap();
+    let mut reader = png::Decoder::new(&file[..]).read_info().unwrap();
+    assert_eq!(BitDepth::Four, reader.info().bit_depth);
+    let png_palette = reader.info().palette.as_deref().unwrap().to_vec();
+    assert_eq!(palette.iter().flat_map(|c| [c.r, c.g, c.b]).collect::<Vec<u8>>(), png_palette);
+
+    let mut buf = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buf).unwrap();
+    let unpacked: Vec<u8> = buf.chunks(24).flat_map(|row| (0..48).map(move |x| (row[x / 2] >> (4 - 4 * (x % 2))) & 15)).collect();
+    assert_eq!(indices, unpacked);
+}
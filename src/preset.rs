@@ -0,0 +1,90 @@
+//! Settings matching common pngquant command lines
+
+use crate::attr::AttributesBuilder;
+use crate::quant::RemapSettings;
+
+/// Named settings for [`Attributes`](crate::Attributes) and remapping, mirroring pngquant's command-line options.
+///
+/// With the `serde` feature, presets are written in config files as `"default"`, `"web"`, etc.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum Preset {
+    /// `pngquant` without options: speed 4, up to 256 colors, full dithering
+    Default,
+    /// `pngquant --quality=65-80`, the usual choice for web images. Fails with [`Error::QualityTooLow`](crate::Error::QualityTooLow) below 65.
+    Web,
+    /// `pngquant --speed=1`: slowest, with the most thorough palette search
+    Best,
+    /// `pngquant --speed=10`: fastest, for generating images on the fly
+    Fast,
+    /// `pngquant --nofs`: no dithering, for pixel art and flat graphics
+    NoDither,
+    /// `pngquant --posterize=4`: colors that are exact in 4 bits per channel, for RGBA4444 textures
+    Rgba4444,
+}
+
+impl Preset {
+    /// Quantization settings of this preset. They can still be changed before calling [`AttributesBuilder::build`].
+    pub fn attributes(self) -> AttributesBuilder {
+        let builder = AttributesBuilder::default();
+        match self {
+            Self::Default | Self::NoDither => builder,
+            Self::Web => builder.quality(65, 80),
+            Self::Best => builder.speed(1),
+            Self::Fast => builder.speed(10),
+            Self::Rgba4444 => builder.min_posterization(4),
+        }
+    }
+
+    /// Remapping settings of this preset, for [`QuantizationResult::set_remap_settings`](crate::QuantizationResult::set_remap_settings)
+    #[must_use]
+    pub fn remap_settings(self) -> RemapSettings {
+        RemapSettings {
+            dithering_level: if self == Self::NoDither { 0. } else { 1. },
+            output_gamma: None,
+        }
+    }
+}
+
+#[test]
+fn presets() {
+    use crate::pal::RGBA;
+
+    let pixels: Vec<_> = (0..64u8).map(|i| RGBA::new(i * 4, 255 - i * 4, i, 255)).collect();
+    for preset in [Preset::Default, Preset::Web, Preset::Best, Preset::Fast, Preset::NoDither, Preset::Rgba4444] {
+        let mut liq = preset.attributes().build().unwrap();
+        let mut img = liq.new_image(&pixels, 8, 8, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        res.set_remap_settings(&preset.remap_settings()).unwrap();
+        assert_eq!(preset.remap_settings().dithering_level, res.remap_settings().dithering_level);
+        let (palette, _) = res.remapped(&mut img).unwrap();
+        if preset == Preset::Rgba4444 {
+            assert!(palette.iter().all(|c| c.r % 17 == 0 && c.g % 17 == 0 && c.b % 17 == 0), "{:?}", palette);
+        }
+    }
+    assert_eq!((1, (65, 80)), (Preset::Best.attributes().build().unwrap().speed(), Preset::Web.attributes().build().unwrap().quality()));
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn settings_serde() {
+    use crate::pal::TransferFunction;
+    use crate::remap::DitherMapMode;
+
+    let builder: AttributesBuilder = serde_json::from_str(r#"{"speed": 7, "min_quality": 50, "target_quality": 70, "dither_map": "always"}"#).unwrap();
+    assert_eq!(AttributesBuilder::default().speed(7).quality(50, 70).dither_map(DitherMapMode::Always), builder);
+    let liq = builder.build().unwrap();
+    assert_eq!((7, (50, 70)), (liq.speed(), liq.quality()));
+
+    let json = serde_json::to_string(&Preset::Rgba4444.attributes()).unwrap();
+    assert_eq!(Preset::Rgba4444.attributes(), serde_json::from_str(&json).unwrap());
+    assert!(serde_json::from_str::<AttributesBuilder>(r#"{"sped": 7}"#).is_err());
+
+    let settings: RemapSettings = serde_json::from_str(r#"{"dithering_level": 0.5, "output_gamma": "linear"}"#).unwrap();
+    assert_eq!((0.5, Some(TransferFunction::Linear)), (settings.dithering_level, settings.output_gamma));
+    let settings: RemapSettings = serde_json::from_str("{}").unwrap();
+    assert_eq!((1., None), (settings.dithering_level, settings.output_gamma));
+    assert_eq!(settings, serde_json::from_str(&serde_json::to_string(&settings).unwrap()).unwrap());
+    assert_eq!(Preset::Web, serde_json::from_str(r#""web""#).unwrap());
+}
@@ -1,20 +1,27 @@
 use crate::attr::{Attributes, ControlFlow};
+use crate::cancel::CancellationToken;
 use crate::error::*;
+use crate::event::{Event, EventCallback};
 use crate::ffi::MagicTag;
 use crate::ffi::{LIQ_FREED_MAGIC, LIQ_RESULT_MAGIC};
 use crate::hist::{FixedColorsSet, HistogramInternal};
 use crate::image::Image;
 use crate::kmeans::Kmeans;
 use crate::mediancut::mediancut;
-use crate::pal::{PalF, PalLen, PalPop, Palette, LIQ_WEIGHT_MSE, MAX_COLORS, MAX_TRANSP_A, RGBA};
-use crate::remap::{mse_to_standard_mse, DitherMapMode, Remapped};
+use crate::packed::{pack_row, BitOrder, PackOptions, PackedFormat};
+use crate::pal::{ComponentOrder, PalF, PalLen, PalPop, Palette, TransferFunction, LIQ_WEIGHT_MSE, MAX_COLORS, MAX_TRANSP_A, RGBA};
+use crate::remap::{mse_to_standard_mse, DitherMapMode, RemapOutput, RemapSink, Remapped};
+use crate::rows::temp_buf;
 use crate::seacow::RowBitmapMut;
+use crate::stats::{QuantizationStats, TrialStats};
+use crate::threads::Threads;
 use crate::OrdFloat;
 use arrayvec::ArrayVec;
 use fallible_collections::FallibleVec;
 use std::cmp::Reverse;
 use std::fmt;
 use std::mem::MaybeUninit;
+use std::time::Instant;
 
 pub struct QuantizationResult {
     pub(crate) magic_header: MagicTag,
@@ -23,22 +30,56 @@ pub struct QuantizationResult {
     progress_callback: Option<Box<dyn Fn(f32) -> ControlFlow + Send + Sync>>,
     pub(crate) int_palette: Palette,
     pub(crate) dither_level: f32,
-    pub(crate) gamma: f64,
+    pub(crate) transfer: TransferFunction,
     pub(crate) palette_error: Option<f64>,
     pub(crate) min_posterization_output: u8,
+    pub(crate) grayscale: bool,
     pub(crate) use_dither_map: DitherMapMode,
+    pub(crate) cancel: CancellationToken,
+    extras: Box<Extras>,
+}
+
+/// Rarely used, so kept out of line to keep `QuantizationResult` small
+struct Extras {
+    event_callback: Option<EventCallback>,
+    stats: QuantizationStats,
+    threads: Threads,
+}
+
+/// Settings of [`QuantizationResult`] that change how images are remapped
+///
+/// With the `serde` feature it can be read from config files. Missing fields keep their defaults.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default, deny_unknown_fields))]
+#[non_exhaustive]
+pub struct RemapSettings {
+    /// 0-1. See [`QuantizationResult::set_dithering_level`].
+    pub dithering_level: f32,
+    /// `None` keeps the transfer function of the input image. See [`QuantizationResult::set_output_gamma`].
+    pub output_gamma: Option<TransferFunction>,
+}
+
+impl Default for RemapSettings {
+    fn default() -> Self {
+        Self {
+            dithering_level: 1.,
+            output_gamma: None,
+        }
+    }
 }
 
 impl QuantizationResult {
-    pub(crate) fn new(attr: &Attributes, hist: HistogramInternal, freeze_result_colors: bool, fixed_colors: &FixedColorsSet, gamma: f64) -> Result<Self, liq_error> {
-        if attr.progress(attr.progress_stage1 as f32) { return Err(LIQ_ABORTED); }
+    pub(crate) fn new(attr: &Attributes, hist: HistogramInternal, freeze_result_colors: bool, fixed_colors: &FixedColorsSet, transfer: TransferFunction, mut stats: QuantizationStats) -> Result<Self, Error> {
+        if attr.progress(attr.progress_stage1 as f32) { return Err(Error::Aborted { stage: Stage::Quantization }); }
         let (max_mse, target_mse, target_mse_is_zero) = attr.target_mse(hist.items.len());
-        let (mut palette, palette_error) = find_best_palette(attr, target_mse, target_mse_is_zero, max_mse, hist, fixed_colors).ok_or(LIQ_VALUE_OUT_OF_RANGE)?;
+        let (mut palette, palette_error) = find_best_palette(attr, target_mse, target_mse_is_zero, max_mse, hist, fixed_colors, &mut stats)
+            .ok_or_else(|| if attr.cancel.is_cancelled() { Error::Aborted { stage: Stage::Quantization } } else { Error::out_of_range("max colors", attr.max_colors) })?;
+        stats.palette_error = palette_error.map(mse_to_standard_mse);
         if freeze_result_colors {
             palette.iter_mut().for_each(|(_, p)| *p = p.to_fixed());
         }
         if attr.progress(attr.progress_stage1 as f32 + attr.progress_stage2 as f32 + attr.progress_stage3 as f32 * 0.95) {
-            return Err(LIQ_ABORTED);
+            return Err(Error::Aborted { stage: Stage::Quantization });
         }
         if let (Some(palette_error), Some(max_mse)) = (palette_error, max_mse) {
             if palette_error > max_mse {
@@ -49,7 +90,7 @@ impl QuantizationResult {
                     mse_to_standard_mse(max_mse),
                     mse_to_quality(max_mse)
                 ));
-                return Err(LIQ_QUALITY_TOO_LOW);
+                return Err(Error::QualityTooLow { quality: mse_to_quality(palette_error), min_quality: mse_to_quality(max_mse) });
             }
         }
 
@@ -58,10 +99,17 @@ impl QuantizationResult {
         Ok(Self {
             magic_header: LIQ_RESULT_MAGIC,
             palette,
-            gamma,
+            transfer,
             palette_error,
             min_posterization_output: attr.min_posterization(),
+            grayscale: attr.grayscale,
             use_dither_map: attr.use_dither_map,
+            cancel: attr.cancel.clone(),
+            extras: Box::new(Extras {
+                event_callback: attr.event_callback.clone(),
+                stats,
+                threads: attr.threads.clone(),
+            }),
             remapped: None,
             progress_callback: None,
             int_palette: Palette {
@@ -72,36 +120,59 @@ impl QuantizationResult {
         })
     }
 
-    pub(crate) fn write_remapped_image_rows_internal(&mut self, image: &mut Image, output_pixels: RowBitmapMut<'_, MaybeUninit<u8>>) -> Result<(), liq_error> {
+    pub(crate) fn write_remapped_image_rows_internal(&mut self, image: &mut Image, output_pixels: RowBitmapMut<'_, MaybeUninit<u8>>) -> Result<(), Error> {
         if image.edges.is_none() && image.dither_map.is_none() && self.use_dither_map != DitherMapMode::None {
             image.contrast_maps()?;
         }
 
-        self.remapped = Some(Box::new(Remapped::new(self, image, output_pixels)?));
+        self.remapped = Some(Box::new(Remapped::new(self, image, RemapOutput::Rows(output_pixels))?));
         Ok(())
     }
 
     /// Set to 1.0 to get nice smooth image
-    pub fn set_dithering_level(&mut self, value: f32) -> liq_error {
+    pub fn set_dithering_level(&mut self, value: f32) -> Result<(), Error> {
         if !(0. ..=1.).contains(&value) {
-            return LIQ_VALUE_OUT_OF_RANGE;
+            return Err(Error::out_of_range("dithering level", value));
         }
 
         self.remapped = None;
         self.dither_level = value;
-        LIQ_OK
+        Ok(())
     }
 
-    /// The default is sRGB gamma (~1/2.2)
-    pub fn set_output_gamma(&mut self, value: f64) -> liq_error {
-        if value <= 0. || value >= 1. {
-            return LIQ_VALUE_OUT_OF_RANGE;
+    /// The default is the gamma or [`TransferFunction`] of the input image (usually sRGB gamma ~1/2.2)
+    pub fn set_output_gamma(&mut self, value: impl Into<TransferFunction>) -> Result<(), Error> {
+        let value = value.into();
+        if let TransferFunction::Gamma(gamma) = value {
+            if gamma <= 0. || gamma >= 1. {
+                return Err(Error::out_of_range("gamma", gamma));
+            }
         }
 
         self.remapped = None;
-        self.gamma = value;
+        self.transfer = value;
+
+        Ok(())
+    }
+
+    /// Dithering level and output gamma, e.g. to save them in a config file
+    #[must_use]
+    pub fn remap_settings(&self) -> RemapSettings {
+        RemapSettings {
+            dithering_level: self.dither_level,
+            output_gamma: Some(self.transfer),
+        }
+    }
 
-        LIQ_OK
+    /// Sets everything in [`RemapSettings`]. Nothing is changed if any setting is invalid.
+    pub fn set_remap_settings(&mut self, settings: &RemapSettings) -> Result<(), Error> {
+        if !(0. ..=1.).contains(&settings.dithering_level) {
+            return Err(Error::out_of_range("dithering level", settings.dithering_level));
+        }
+        if let Some(transfer) = settings.output_gamma {
+            self.set_output_gamma(transfer)?;
+        }
+        self.set_dithering_level(settings.dithering_level)
     }
 
     /// Approximate gamma correction value used for the output
@@ -110,7 +181,14 @@ impl QuantizationResult {
     #[inline]
     #[must_use]
     pub fn output_gamma(&self) -> f64 {
-        self.gamma
+        self.transfer.approx_gamma()
+    }
+
+    /// Transfer function the palette is encoded with
+    #[inline]
+    #[must_use]
+    pub fn output_transfer(&self) -> TransferFunction {
+        self.transfer
     }
 
     /// Number 0-100 guessing how nice the input image will look if remapped to this palette
@@ -131,6 +209,18 @@ impl QuantizationResult {
             .map(mse_to_standard_mse)
     }
 
+    /// Timings, histogram sizes and palette errors of each stage of quantization and the last remapping
+    #[must_use]
+    pub fn stats(&self) -> QuantizationStats {
+        let mut stats = self.extras.stats.clone();
+        if let Some(re) = &self.remapped {
+            stats.remap_time = Some(re.duration);
+            stats.dither_map_time = re.dither_map_duration;
+            stats.remapping_error = re.palette_error.map(mse_to_standard_mse);
+        }
+        stats
+    }
+
     pub fn remapping_quality(&self) -> Option<u8> {
         self.remapped.as_ref()
             .and_then(|re| re.palette_error)
@@ -148,6 +238,17 @@ impl QuantizationResult {
         out
     }
 
+    /// Final palette, copied and stored in the given channel order
+    ///
+    /// Same as `palette_vec()` for `ComponentOrder::RGBA`
+    #[must_use]
+    pub fn palette_ordered(&mut self, order: ComponentOrder) -> Vec<[u8; 4]> {
+        let pal = self.palette();
+        let mut out: Vec<[u8; 4]> = FallibleVec::try_with_capacity(pal.len()).unwrap();
+        out.extend(pal.iter().map(move |&px| order.pack(px)));
+        out
+    }
+
     /// Final palette
     ///
     /// It's slighly better if you get palette from the `remapped()` call instead
@@ -164,7 +265,7 @@ impl QuantizationResult {
             }
             None => {
                 if self.int_palette.count == 0 {
-                    self.int_palette = Remapped::make_int_palette(&mut self.palette, self.gamma, self.min_posterization_output);
+                    self.int_palette = Remapped::make_int_palette(&mut self.palette, self.transfer, self.min_posterization_output, self.grayscale);
                 }
                 &self.int_palette
             },
@@ -176,8 +277,39 @@ impl QuantizationResult {
         self.progress_callback = Some(Box::new(callback));
     }
 
+    /// Called after the dither map is made and after remapping. See [`Attributes::set_event_callback`].
+    ///
+    /// By default it's the callback of the [`Attributes`] that made this result.
+    #[inline]
+    pub fn set_event_callback<F: Fn(&Event) + Send + Sync + 'static>(&mut self, callback: F) {
+        self.extras.event_callback = Some(std::sync::Arc::new(callback));
+    }
+
+    #[inline]
+    pub(crate) fn emit(&self, event: Event) {
+        if let Some(f) = &self.extras.event_callback {
+            f(&event);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn threads(&self) -> &Threads {
+        &self.extras.threads
+    }
+
+    /// Remapping will fail with [`Error::Aborted`] soon after the token is cancelled.
+    ///
+    /// By default it's the token of the [`Attributes`] that made this result.
+    #[inline]
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel = token;
+    }
+
     // true == abort
     pub(crate) fn remap_progress(&self, percent: f32) -> bool {
+        if self.cancel.is_cancelled() {
+            return true;
+        }
         if let Some(cb) = &self.progress_callback {
             cb(percent) == ControlFlow::Break
         } else {
@@ -188,11 +320,11 @@ impl QuantizationResult {
     /// Remap image into a palette + indices.
     ///
     /// Returns the palette and a 1-byte-per-pixel uncompressed bitmap
-    pub fn remapped(&mut self, image: &mut Image<'_, '_>) -> Result<(Vec<RGBA>, Vec<u8>), liq_error> {
+    pub fn remapped(&mut self, image: &mut Image<'_, '_>) -> Result<(Vec<RGBA>, Vec<u8>), Error> {
         let len = image.width() * image.height();
         // Capacity is essential here, as it creates uninitialized buffer
         unsafe {
-            let mut buf: Vec<u8> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+            let mut buf: Vec<u8> = FallibleVec::try_with_capacity(len).map_err(|_| Error::OutOfMemory)?;
             let uninit_slice = std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<MaybeUninit<u8>>(), buf.capacity());
             self.remap_into(image, uninit_slice)?;
             buf.set_len(uninit_slice.len());
@@ -209,13 +341,152 @@ impl QuantizationResult {
     /// You should call `palette()` or `palette_ref()` _after_ this call, but not before it,
     /// because remapping changes the palette.
     #[inline]
-    pub fn remap_into(&mut self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<u8>]) -> Result<(), liq_error> {
+    pub fn remap_into(&mut self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<u8>]) -> Result<(), Error> {
         let required_size = (image.width()) * (image.height());
-        let output_buf = output_buf.get_mut(0..required_size).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+        Error::check_len(required_size, output_buf.len())?;
+        let output_buf = &mut output_buf[..required_size];
 
         let rows = RowBitmapMut::new_contiguous(output_buf, image.width());
         self.write_remapped_image_rows_internal(image, rows)
     }
+
+    /// Remap image and pass rows of indices to the sink as soon as they're ready, without allocating the whole output.
+    ///
+    /// The sink gets the final palette before the first row. A closure `|row_index, indices| {}` can be used as the sink.
+    ///
+    /// Together with [`Image::new_fn`] or images too large to cache, this remaps in bounded memory.
+    /// The dither map can't be generated from streamed output, so dithering is guided by the edges of the image only.
+    pub fn remap_to_sink(&mut self, image: &mut Image<'_, '_>, sink: &mut dyn RemapSink) -> Result<(), Error> {
+        if image.edges.is_none() && image.dither_map.is_none() && self.use_dither_map != DitherMapMode::None {
+            image.contrast_maps()?;
+        }
+
+        self.remapped = Some(Box::new(Remapped::new(self, image, RemapOutput::Sink(sink))?));
+        Ok(())
+    }
+
+    /// Remap image into a palette + indices packed 1, 2, 4 or 8 bits per pixel.
+    ///
+    /// Returns the palette, the packed rows and their layout.
+    ///
+    /// The image is remapped the same way as in [`QuantizationResult::remapped`], and each row is packed in place afterwards.
+    pub fn remapped_packed(&mut self, image: &mut Image<'_, '_>, options: PackOptions) -> Result<(Vec<RGBA>, Vec<u8>, PackedFormat), Error> {
+        let format = options.format(image.width(), self.palette.len())?;
+        let len = image.width().max(format.stride) * image.height();
+        let mut buf: Vec<u8> = FallibleVec::try_with_capacity(len).map_err(|_| Error::OutOfMemory)?;
+        buf.resize(len, 0);
+        self.remap_and_pack(image, format, options.bit_order, &mut buf)?;
+        buf.truncate(format.stride * image.height());
+        Ok((self.palette_vec(), buf, format))
+    }
+
+    /// Remap image into an existing buffer of packed indices.
+    ///
+    /// The buffer must be at least `stride * height` bytes for the format returned by [`PackOptions::format`].
+    /// With automatic bit depth, size it for the largest depth the palette may need.
+    /// Nothing past that is written. If the stride is less than the width, a temporary 1-byte-per-pixel image is allocated.
+    ///
+    /// Otherwise the same as [`QuantizationResult::remapped_packed`].
+    pub fn remap_into_packed(&mut self, image: &mut Image<'_, '_>, options: PackOptions, output_buf: &mut [u8]) -> Result<PackedFormat, Error> {
+        let format = options.format(image.width(), self.palette.len())?;
+        let required_size = format.stride * image.height();
+        Error::check_len(required_size, output_buf.len())?;
+        let len = image.width().max(format.stride) * image.height();
+        if len == required_size {
+            self.remap_and_pack(image, format, options.bit_order, &mut output_buf[..len])?;
+        } else {
+            let mut buf: Vec<u8> = FallibleVec::try_with_capacity(len).map_err(|_| Error::OutOfMemory)?;
+            buf.resize(len, 0);
+            self.remap_and_pack(image, format, options.bit_order, &mut buf)?;
+            output_buf[..required_size].copy_from_slice(&buf[..required_size]);
+        }
+        Ok(format)
+    }
+
+    /// `buf` has `max(width, stride)` bytes per row. Packed rows are moved to the start of the buffer.
+    fn remap_and_pack(&mut self, image: &mut Image<'_, '_>, format: PackedFormat, bit_order: BitOrder, buf: &mut [u8]) -> Result<(), Error> {
+        let width = image.width();
+        let row_len = width.max(format.stride);
+        let mut row_pointers: Vec<*mut MaybeUninit<u8>> = buf.chunks_exact_mut(row_len)
+            .map(|row| row.as_mut_ptr().cast::<MaybeUninit<u8>>())
+            .collect();
+        let rows = unsafe { RowBitmapMut::new(&mut row_pointers, width) };
+        self.write_remapped_image_rows_internal(image, rows)?;
+
+        // a packed row never starts after its unpacked row, and never reaches the next one
+        let mut temp_row = vec![0; width];
+        for y in 0..image.height() {
+            temp_row.copy_from_slice(&buf[y * row_len..][..width]);
+            pack_row(&temp_row, &mut buf[y * format.stride..][..format.stride], format.bits_per_pixel, bit_order);
+        }
+        Ok(())
+    }
+
+    /// Remap image and return palette colors of its pixels, e.g. for previews.
+    ///
+    /// If the image has a background set (see [`Image::set_background`]), semi-transparent colors are composited over it.
+    ///
+    /// The image is remapped the same way as in [`QuantizationResult::remapped`], with indices written into the output buffer,
+    /// and then expanded to colors in place. That costs one more pass over the output, but no extra memory.
+    pub fn remapped_rgba(&mut self, image: &mut Image<'_, '_>) -> Result<Vec<RGBA>, Error> {
+        let len = image.width() * image.height();
+        // Capacity is essential here, as it creates uninitialized buffer
+        unsafe {
+            let mut buf: Vec<RGBA> = FallibleVec::try_with_capacity(len).map_err(|_| Error::OutOfMemory)?;
+            let uninit_slice = std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<MaybeUninit<RGBA>>(), buf.capacity());
+            self.remap_into_rgba(image, uninit_slice)?;
+            buf.set_len(uninit_slice.len());
+            Ok(buf)
+        }
+    }
+
+    /// Remap image into an existing buffer of RGBA pixels.
+    ///
+    /// Otherwise the same as [`QuantizationResult::remapped_rgba`].
+    pub fn remap_into_rgba(&mut self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<RGBA>]) -> Result<(), Error> {
+        let width = image.width();
+        Error::check_len(width * image.height(), output_buf.len())?;
+        let output_buf = &mut output_buf[..width * image.height()];
+
+        // Indices are written to the last quarter of each row's bytes, and then expanded in place.
+        // Expanding left to right never overwrites an index that hasn't been read yet.
+        let mut row_pointers: Vec<*mut MaybeUninit<u8>> = output_buf.chunks_exact_mut(width)
+            .map(|row| unsafe { row.as_mut_ptr().cast::<MaybeUninit<u8>>().add(width * 3) })
+            .collect();
+        let rows = unsafe { RowBitmapMut::new(&mut row_pointers, width) };
+        self.write_remapped_image_rows_internal(image, rows)?;
+
+        let palette = self.int_palette().as_slice();
+        let background = image.background.as_ref().map(|bg| bg.px.rgba_rows_iter()).transpose()?;
+        let mut temp_row = temp_buf(background.as_ref().map_or(0, |bg| bg.temp_row_width()));
+        for (y, row) in output_buf.chunks_exact_mut(width).enumerate() {
+            let bg_row = background.as_ref().map(|bg| bg.row_rgba(&mut temp_row, y)).unwrap_or(&[]);
+            let row = row.as_mut_ptr();
+            for x in 0..width {
+                let mut px = palette[unsafe { *row.cast::<u8>().add(width * 3 + x) } as usize];
+                if let Some(&bg) = bg_row.get(x) {
+                    px = composite_over(px, bg);
+                }
+                unsafe { row.add(x).write(MaybeUninit::new(px)) };
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `fg` over `bg`, in the output's color space
+fn composite_over(fg: RGBA, bg: RGBA) -> RGBA {
+    if fg.a == 255 || bg.a == 0 {
+        return fg;
+    }
+    let fg_a = fg.a as u32;
+    let bg_a = bg.a as u32 * (255 - fg_a) / 255;
+    let a = fg_a + bg_a;
+    if a == 0 {
+        return fg;
+    }
+    let mix = |f: u8, b: u8| ((f as u32 * fg_a + b as u32 * bg_a + a / 2) / a) as u8;
+    RGBA::new(mix(fg.r, bg.r), mix(fg.g, bg.g), mix(fg.b, bg.b), a as u8)
 }
 
 fn sort_palette(attr: &Attributes, palette: &mut PalF) {
@@ -262,7 +533,7 @@ impl fmt::Debug for QuantizationResult {
 ///
 ///  feedback_loop_trials controls how long the search will take. < 0 skips the iteration.
 #[allow(clippy::or_fun_call)]
-pub(crate) fn find_best_palette(attr: &Attributes, target_mse: f64, target_mse_is_zero: bool, max_mse: Option<f64>, mut hist: HistogramInternal, fixed_colors: &FixedColorsSet) -> Option<(PalF, Option<f64>)> {
+pub(crate) fn find_best_palette(attr: &Attributes, target_mse: f64, target_mse_is_zero: bool, max_mse: Option<f64>, mut hist: HistogramInternal, fixed_colors: &FixedColorsSet, stats: &mut QuantizationStats) -> Option<(PalF, Option<f64>)> {
     let few_input_colors = hist.items.len() + fixed_colors.len() <= attr.max_colors as usize;
     // actual target_mse passed to this method has extra diff from posterization
     if few_input_colors && target_mse_is_zero {
@@ -276,10 +547,15 @@ pub(crate) fn find_best_palette(attr: &Attributes, target_mse: f64, target_mse_i
     let mut target_mse_overshoot = if total_trials > 0 { 1.05 } else { 1. };
     let mut fails_in_a_row = 0;
     let mut palette_error = None;
+    let mut trial = 0;
     let mut palette = loop {
+        let start = Instant::now();
         let max_mse_per_color = target_mse.max(palette_error.unwrap_or(quality_to_mse(1))).max(quality_to_mse(51)) * 1.2;
-        let mut new_palette = mediancut(&mut hist, max_colors - fixed_colors.len() as PalLen, target_mse * target_mse_overshoot, max_mse_per_color)
+        let mut new_palette = mediancut(&mut hist, max_colors - fixed_colors.len() as PalLen, target_mse * target_mse_overshoot, max_mse_per_color, &attr.cancel)
             .with_fixed_colors(max_colors, fixed_colors);
+        let median_cut_time = start.elapsed();
+        stats.median_cut_time += median_cut_time;
+        attr.emit(Event::MedianCut { colors: new_palette.len(), duration: median_cut_time });
 
         let stage_done = 1. - (trials_left.max(0) as f32 / (total_trials + 1) as f32).powi(2);
         let overall_done = attr.progress_stage1 as f32 + stage_done * attr.progress_stage2 as f32;
@@ -288,7 +564,25 @@ pub(crate) fn find_best_palette(attr: &Attributes, target_mse: f64, target_mse_i
         if trials_left <= 0 { break Some(new_palette); }
 
         let first_run_of_target_mse = best_palette.is_none() && target_mse > 0.;
-        let total_error = Kmeans::iteration(&mut hist, &mut new_palette, !first_run_of_target_mse);
+        let total_error = Kmeans::iteration(&mut hist, &mut new_palette, !first_run_of_target_mse, &attr.cancel, &attr.threads);
+        if attr.cancel.is_cancelled() {
+            return None;
+        }
+        trial += 1;
+        let trial_stats = TrialStats {
+            median_cut_time,
+            kmeans_time: start.elapsed() - median_cut_time,
+            colors: new_palette.len(),
+            palette_error: mse_to_standard_mse(total_error),
+        };
+        stats.trials.push(trial_stats);
+        attr.emit(Event::FeedbackTrial {
+            trial,
+            trials: total_trials as u16,
+            colors: trial_stats.colors,
+            palette_error: trial_stats.palette_error,
+            duration: start.elapsed(),
+        });
         if best_palette.is_none() || total_error < palette_error.unwrap_or(f64::MAX) || (total_error <= target_mse && new_palette.len() < max_colors as usize) {
             if total_error < target_mse && total_error > 0. {
                 target_mse_overshoot = if (target_mse_overshoot * 1.25) < (target_mse / total_error) {target_mse_overshoot * 1.25 } else {target_mse / total_error }; // if number of colors could be reduced, try to keep it that way
@@ -307,14 +601,18 @@ pub(crate) fn find_best_palette(attr: &Attributes, target_mse: f64, target_mse_i
             break best_palette;
         }
     }?;
+    stats.trials_palette_error = palette_error.map(mse_to_standard_mse);
 
-    refine_palette(&mut palette, attr, &mut hist, max_mse, &mut palette_error);
+    refine_palette(&mut palette, attr, &mut hist, max_mse, &mut palette_error, stats);
+    if attr.cancel.is_cancelled() {
+        return None;
+    }
 
     Some((palette, palette_error))
 }
 
 
-fn refine_palette(palette: &mut PalF, attr: &Attributes, hist: &mut HistogramInternal, max_mse: Option<f64>, palette_error: &mut Option<f64>) {
+fn refine_palette(palette: &mut PalF, attr: &Attributes, hist: &mut HistogramInternal, max_mse: Option<f64>, palette_error: &mut Option<f64>, stats: &mut QuantizationStats) {
     let (iterations, iteration_limit) = attr.kmeans_iterations(hist.items.len(), palette_error.is_some());
     if iterations > 0 {
         attr.verbose_print("  moving colormap towards local minimum");
@@ -322,12 +620,22 @@ fn refine_palette(palette: &mut PalF, attr: &Attributes, hist: &mut HistogramInt
         while i < iterations {
             let stage_done = i as f32 / iterations as f32;
             let overall_done = attr.progress_stage1 as f32 + attr.progress_stage2 as f32 + stage_done * attr.progress_stage3 as f32 * 0.89;
-            if attr.progress(overall_done) {
+            if attr.progress(overall_done) || attr.cancel.is_cancelled() {
                 break;
             }
 
-            let pal_err = Kmeans::iteration(hist, palette, false);
+            let start = Instant::now();
+            let pal_err = Kmeans::iteration(hist, palette, false, &attr.cancel, &attr.threads);
             debug_assert!(pal_err < 1e20);
+            let duration = start.elapsed();
+            stats.kmeans_time += duration;
+            stats.kmeans_iterations += 1;
+            attr.emit(Event::KmeansIteration {
+                iteration: i + 1,
+                iterations,
+                palette_error: mse_to_standard_mse(pal_err),
+                duration,
+            });
             let previous_palette_error = *palette_error;
             *palette_error = Some(pal_err);
 
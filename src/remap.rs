@@ -1,97 +1,205 @@
+use crate::attr::ControlFlow;
+use crate::cancel::CancellationToken;
 use crate::error::*;
+use crate::event::Event;
 use crate::image::Image;
 use crate::kmeans::Kmeans;
 use crate::nearest::Nearest;
-use crate::pal::{ARGBF, LIQ_WEIGHT_MSE, MIN_OPAQUE_A, PalF, PalIndex, Palette, f_pixel, gamma_lut};
+use crate::pal::{ARGBF, LIQ_WEIGHT_MSE, MIN_OPAQUE_A, PalF, PalIndex, Palette, RGBA, TransferFunction, f_pixel, gamma_lut};
 use crate::quant::{quality_to_mse, QuantizationResult};
-use crate::rows::temp_buf;
+use crate::rows::{temp_buf, DynamicRowsIter};
 use crate::seacow::{RowBitmap, RowBitmapMut};
-use rayon::iter::ParallelBridge;
-use rayon::iter::ParallelIterator;
+use crate::threads::Threads;
+#[cfg(not(feature = "threads"))]
+use crate::threads::ThreadLocal;
 use rgb::ComponentMap;
 use std::cell::RefCell;
 use std::mem::MaybeUninit;
+use std::time::{Duration, Instant};
+#[cfg(feature = "threads")]
 use thread_local::ThreadLocal;
 
+/// Whether remapping makes a map of edges and noise to decide where to dither
 #[repr(u8)]
-#[derive(Eq, PartialEq, Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "snake_case"))]
 pub enum DitherMapMode {
+    /// Dither evenly everywhere
     None = 0,
+    /// Except for very large images
     Enabled = 1,
+    /// Even for very large images
     Always = 2,
 }
 
+/// Where remapped indices are written
+pub(crate) enum RemapOutput<'a, 'b> {
+    Rows(RowBitmapMut<'b, MaybeUninit<u8>>),
+    Sink(&'a mut dyn RemapSink),
+}
+
 pub(crate) struct Remapped {
     pub(crate) int_palette: Palette,
     pub(crate) palette_error: Option<f64>,
+    pub(crate) duration: Duration,
+    pub(crate) dither_map_duration: Option<Duration>,
 }
 
-#[inline(never)]
-pub(crate) fn remap_to_palette<'x, 'b: 'x>(image: &mut Image, output_pixels: &'x mut RowBitmapMut<'b, MaybeUninit<u8>>, palette: &mut PalF) -> Result<(f64, RowBitmap<'x, u8>), liq_error> {
-    let width = image.width();
-
-    let n = Nearest::new(palette);
-    let colors = palette.as_slice();
-    let palette_len = colors.len();
+/// Receives remapped rows as soon as they're ready. See [`QuantizationResult::remap_to_sink`].
+///
+/// Closures taking `(row_index, indices)` implement this trait.
+pub trait RemapSink {
+    /// Called once, before any rows. The palette won't change afterwards.
+    fn palette(&mut self, _palette: &[RGBA]) -> ControlFlow {
+        ControlFlow::Continue
+    }
 
-    let mut background = image.background.as_mut();
-    let transparent_index = if background.is_some() {
-        n.search(&f_pixel::default(), 0).0 as i16
-    } else { -1 };
+    /// Rows are written in order, one byte per pixel. `Break` aborts remapping.
+    fn write_row(&mut self, row_index: usize, indices: &[u8]) -> ControlFlow;
+}
 
-    if background.is_some() && colors[transparent_index as usize].a > MIN_OPAQUE_A {
-        background = None;
+impl<F: FnMut(usize, &[u8])> RemapSink for F {
+    #[inline]
+    fn write_row(&mut self, row_index: usize, indices: &[u8]) -> ControlFlow {
+        self(row_index, indices);
+        ControlFlow::Continue
     }
+}
+
+/// Nearest-color remapping of any subset of rows, in parallel
+struct RowRemapper<'a, 'pixels, 'rows> {
+    n: Nearest<'a>,
+    colors: &'a [f_pixel],
+    transparent_index: i16,
+    input_rows: DynamicRowsIter<'a, 'pixels, 'rows>,
+    background: Option<DynamicRowsIter<'a, 'pixels, 'rows>>,
+    #[allow(clippy::type_complexity)]
+    tls: ThreadLocal<RefCell<(Kmeans, Box<[MaybeUninit<RGBA>]>, Box<[MaybeUninit<f_pixel>]>, Box<[MaybeUninit<f_pixel>]>)>>,
+    width: usize,
+    /// Pixels are gray, so the palette is refined in luma and alpha only
+    grayscale: bool,
+    cancel: &'a CancellationToken,
+    threads: &'a Threads,
+}
 
-    let tls = ThreadLocal::new();
-    let per_thread_buffers = move || (RefCell::new((Kmeans::new(palette_len), temp_buf(width), temp_buf(width), temp_buf(width))));
+impl<'a, 'pixels, 'rows> RowRemapper<'a, 'pixels, 'rows> {
+    fn new(image: &'a mut Image<'pixels, 'rows>, palette: &'a PalF, cancel: &'a CancellationToken, threads: &'a Threads) -> Result<Self, Error> {
+        let width = image.width();
+        let grayscale = image.px.grayscale;
 
-    let tls_tmp1 = tls.get_or(per_thread_buffers);
-    let mut tls_tmp = tls_tmp1.borrow_mut();
+        let n = Nearest::new(palette);
+        let colors = palette.as_slice();
 
-    let input_rows = image.px.rows_iter(&mut tls_tmp.1)?;
-    let background = background.map(|bg| bg.px.rows_iter(&mut tls_tmp.1)).transpose()?;
-    drop(tls_tmp);
+        let mut background = image.background.as_mut();
+        let transparent_index = if background.is_some() {
+            n.search(&f_pixel::default(), 0).0 as i16
+        } else { -1 };
 
-    let remapping_error = output_pixels.rows_mut().enumerate().par_bridge().map(|(row, output_pixels_row)| {
-        let mut remapping_error = 0.;
-        let (kmeans, temp_row, temp_row_f, temp_row_f_bg) = &mut *tls.get_or(per_thread_buffers).borrow_mut();
+        if background.is_some() && colors[transparent_index as usize].a > MIN_OPAQUE_A {
+            background = None;
+        }
 
-        let output_pixels_row = &mut output_pixels_row[..width];
-        let row_pixels = &input_rows.row_f2(temp_row, temp_row_f, row)[..width];
-        let bg_pixels = if let Some(background) = &background  {
-            &background.row_f2(temp_row, temp_row_f_bg, row)[..width]
-        } else { &[] };
+        let mut temp_row = temp_buf(image.px.temp_row_width());
+        let input_rows = image.px.rows_iter(&mut temp_row)?;
+        let background = background.map(|bg| bg.px.rows_iter(&mut temp_buf(bg.px.temp_row_width()))).transpose()?;
 
-        let mut last_match = 0;
-        for (col, (inp, out)) in row_pixels.iter().zip(output_pixels_row).enumerate() {
-            let (idx, mut diff) = n.search(inp, last_match);
-            last_match = idx;
-            if !bg_pixels.is_empty() {
-                let bg_diff = bg_pixels[col].diff(&colors[last_match as usize]);
-                if bg_diff <= diff {
-                    diff = bg_diff;
-                    last_match = transparent_index as PalIndex;
-                }
+        Ok(Self { n, colors, transparent_index, input_rows, background, tls: ThreadLocal::new(), width, grayscale, cancel, threads })
+    }
+
+    /// Returns sum of errors. If cancelled, rows are skipped and left uninitialized.
+    fn remap_rows<'o>(&self, output_rows: impl Iterator<Item = (usize, &'o mut [MaybeUninit<u8>])> + Send) -> f64 {
+        let width = self.width;
+        let palette_len = self.colors.len();
+        let grayscale = self.grayscale;
+        let temp_width = self.input_rows.temp_row_width().max(self.background.as_ref().map_or(0, |bg| bg.temp_row_width()));
+        let per_thread_buffers = move || RefCell::new((Kmeans::new(palette_len, grayscale), temp_buf(temp_width), temp_buf(temp_width), temp_buf(temp_width)));
+
+        self.threads.sum(output_rows, |(row, output_pixels_row)| {
+            let mut remapping_error = 0.;
+            if self.cancel.is_cancelled() {
+                return remapping_error;
             }
-            out.write(last_match);
-            remapping_error += diff as f64;
-            if last_match as i16 != transparent_index {
-                kmeans.update_color(*inp, 1., last_match);
+            let (kmeans, temp_row, temp_row_f, temp_row_f_bg) = &mut *self.tls.get_or(per_thread_buffers).borrow_mut();
+
+            let output_pixels_row = &mut output_pixels_row[..width];
+            let row_pixels = &self.input_rows.row_f2(temp_row, temp_row_f, row)[..width];
+            let bg_pixels = if let Some(background) = &self.background  {
+                &background.row_f2(temp_row, temp_row_f_bg, row)[..width]
+            } else { &[] };
+
+            let mut last_match = 0;
+            for (col, (inp, out)) in row_pixels.iter().zip(output_pixels_row).enumerate() {
+                let (idx, mut diff) = self.n.search(inp, last_match);
+                last_match = idx;
+                if !bg_pixels.is_empty() {
+                    let bg_diff = bg_pixels[col].diff(&self.colors[last_match as usize]);
+                    if bg_diff <= diff {
+                        diff = bg_diff;
+                        last_match = self.transparent_index as PalIndex;
+                    }
+                }
+                out.write(last_match);
+                remapping_error += diff as f64;
+                if last_match as i16 != self.transparent_index {
+                    kmeans.update_color(*inp, 1., last_match);
+                }
             }
-        }
-        remapping_error
-    })
-    .sum::<f64>();
+            remapping_error
+        })
+    }
 
-    if let Some(kmeans) = tls.into_iter()
-        .map(|t| RefCell::into_inner(t).0)
-        .reduce(Kmeans::merge) { kmeans.finalize(palette); }
+    fn into_kmeans(self) -> Option<Kmeans> {
+        self.tls.into_iter()
+            .map(|t| RefCell::into_inner(t).0)
+            .reduce(Kmeans::merge)
+    }
+}
+
+#[inline(never)]
+pub(crate) fn remap_to_palette<'x, 'b: 'x>(image: &mut Image, output_pixels: &'x mut RowBitmapMut<'b, MaybeUninit<u8>>, palette: &mut PalF, cancel: &CancellationToken, threads: &Threads) -> Result<(f64, RowBitmap<'x, u8>), Error> {
+    let num_pixels = image.width() * image.height();
+    let remapper = RowRemapper::new(image, palette, cancel, threads)?;
+    let remapping_error = remapper.remap_rows(output_pixels.rows_mut().enumerate());
+    if cancel.is_cancelled() {
+        return Err(Error::Aborted { stage: Stage::Remapping });
+    }
+
+    if let Some(kmeans) = remapper.into_kmeans() { kmeans.finalize(palette); }
 
-    let remapping_error = remapping_error / (image.px.width * image.px.height) as f64;
+    let remapping_error = remapping_error / num_pixels as f64;
     Ok((remapping_error, unsafe { output_pixels.assume_init() }))
 }
 
+/// Remaps a band of rows at a time, and passes them to the sink in order.
+///
+/// Unlike `remap_to_palette`, it doesn't update the palette, since the sink has already seen it.
+#[inline(never)]
+fn remap_to_palette_sink(image: &mut Image, sink: &mut dyn RemapSink, palette: &PalF, cancel: &CancellationToken, threads: &Threads) -> Result<f64, Error> {
+    let width = image.width();
+    let height = image.height();
+    let band_height = ((1 << 16) / width).max(threads.current_num_threads()).min(height.max(1));
+    let mut band = temp_buf::<u8>(width * band_height);
+
+    let remapper = RowRemapper::new(image, palette, cancel, threads)?;
+    let mut remapping_error = 0.;
+    for band_start in (0..height).step_by(band_height) {
+        let rows = (height - band_start).min(band_height);
+        let band = &mut band[..width * rows];
+        remapping_error += remapper.remap_rows(band.chunks_exact_mut(width).enumerate().map(move |(y, row)| (band_start + y, row)));
+        if cancel.is_cancelled() {
+            return Err(Error::Aborted { stage: Stage::Remapping });
+        }
+        // just initialized
+        let band = unsafe { std::slice::from_raw_parts(band.as_ptr().cast::<u8>(), band.len()) };
+        for (y, row) in band.chunks_exact(width).enumerate() {
+            if sink.write_row(band_start + y, row) == ControlFlow::Break {
+                return Err(Error::Aborted { stage: Stage::Remapping });
+            }
+        }
+    }
+    Ok(remapping_error / (width * height) as f64)
+}
+
 fn get_dithered_pixel(dither_level: f32, max_dither_error: f32, thiserr: f_pixel, px: f_pixel) -> f_pixel {
     let s = thiserr.0 * dither_level;
     // This prevents gaudy green pixels popping out of the blue (or red or black! ;)
@@ -135,21 +243,20 @@ fn get_dithered_pixel(dither_level: f32, max_dither_error: f32, thiserr: f_pixel
 ///
 ///  If output_image_is_remapped is true, only pixels noticeably changed by error diffusion will be written to output image.
 #[inline(never)]
-pub(crate) fn remap_to_palette_floyd(input_image: &mut Image, mut output_pixels: RowBitmapMut<'_, MaybeUninit<u8>>, quant: &QuantizationResult, max_dither_error: f32, output_image_is_remapped: bool) -> Result<(), liq_error> {
+pub(crate) fn remap_to_palette_floyd(input_image: &mut Image, output: RemapOutput<'_, '_>, quant: &QuantizationResult, max_dither_error: f32, output_image_is_remapped: bool) -> Result<(), Error> {
     let progress_stage1 = if quant.use_dither_map != DitherMapMode::None { 20 } else { 0 };
 
     let width = input_image.width();
     let height = input_image.height();
 
-    let mut temp_row = temp_buf(width);
-
     let dither_map = if quant.use_dither_map != DitherMapMode::None {
         input_image.dither_map.as_deref().or(input_image.edges.as_deref()).unwrap_or(&[])
     } else {
         &[]
     };
-    let mut input_image_iter = input_image.px.rows_iter(&mut temp_row)?;
-    let mut background = input_image.background.as_mut().map(|bg| bg.px.rows_iter(&mut temp_row)).transpose()?;
+    let temp_width = input_image.px.temp_row_width().max(input_image.background.as_ref().map_or(0, |bg| bg.px.temp_row_width()));
+    let mut input_image_iter = input_image.px.rows_iter(&mut temp_buf(input_image.px.temp_row_width()))?;
+    let mut background = input_image.background.as_mut().map(|bg| bg.px.rows_iter(&mut temp_buf(bg.px.temp_row_width()))).transpose()?;
 
     let errwidth = width + 2; // +2 saves from checking out of bounds access
     let mut thiserr_data = vec![f_pixel::default(); errwidth * 2];
@@ -167,11 +274,23 @@ pub(crate) fn remap_to_palette_floyd(input_image: &mut Image, mut output_pixels:
         base_dithering_level *= 1. / 255.; // dither_map is in 0-255 scale
     }
     let mut scan_forward = true;
-    let mut temp_row = temp_buf(width);
+    let mut temp_row = temp_buf(temp_width);
 
-    for (row, output_pixels_row) in output_pixels.rows_mut().enumerate() {
+    let (mut output_pixels, mut sink) = match output {
+        RemapOutput::Rows(output_pixels) => (Some(output_pixels), None),
+        RemapOutput::Sink(sink) => (None, Some(sink)),
+    };
+    let mut output_rows = output_pixels.as_mut().map(|o| o.rows_mut());
+    let mut sink_row = sink.is_some().then(|| temp_buf::<u8>(width));
+
+    for row in 0..height {
+        let output_pixels_row = match (&mut output_rows, &mut sink_row) {
+            (Some(rows), _) => rows.next().ok_or(Error::BufferTooSmall { expected: height, actual: row })?,
+            (None, Some(sink_row)) => &mut sink_row[..],
+            (None, None) => unreachable!(),
+        };
         if quant.remap_progress(progress_stage1 as f32 + row as f32 * (100. - progress_stage1 as f32) / height as f32) {
-            return Err(LIQ_ABORTED);
+            return Err(Error::Aborted { stage: Stage::Remapping });
         }
         nexterr.fill_with(f_pixel::default);
         let mut col = if scan_forward { 0 } else { width - 1 };
@@ -254,6 +373,13 @@ pub(crate) fn remap_to_palette_floyd(input_image: &mut Image, mut output_pixels:
                 col -= 1;
             }
         }
+        if let Some(sink) = &mut sink {
+            // just initialized
+            let output_pixels_row = unsafe { std::slice::from_raw_parts(output_pixels_row.as_ptr().cast::<u8>(), width) };
+            if sink.write_row(row, output_pixels_row) == ControlFlow::Break {
+                return Err(Error::Aborted { stage: Stage::Remapping });
+            }
+        }
         std::mem::swap(&mut thiserr, &mut nexterr);
         scan_forward = !scan_forward;
     }
@@ -262,62 +388,94 @@ pub(crate) fn remap_to_palette_floyd(input_image: &mut Image, mut output_pixels:
 
 impl Remapped {
     #[allow(clippy::or_fun_call)]
-    pub fn new(result: &QuantizationResult, image: &mut Image, mut output_pixels: RowBitmapMut<'_, MaybeUninit<u8>>) -> Result<Self, liq_error> {
+    pub fn new(result: &QuantizationResult, image: &mut Image, mut output: RemapOutput<'_, '_>) -> Result<Self, Error> {
+        let start = Instant::now();
         let mut palette = result.palette.clone();
         let progress_stage1 = if result.use_dither_map != DitherMapMode::None { 20 } else { 0 };
 
         let posterize = result.min_posterization_output;
         if result.remap_progress(progress_stage1 as f32 * 0.25) {
-            return Err(LIQ_ABORTED);
+            return Err(Error::Aborted { stage: Stage::Remapping });
         }
 
         let mut palette_error = result.palette_error;
+        let mut dither_map_duration = None;
         let int_palette;
         if result.dither_level == 0. {
-            int_palette = Self::make_int_palette(&mut palette, result.gamma, posterize);
-            palette_error = Some(remap_to_palette(image, &mut output_pixels, &mut palette)?.0);
+            int_palette = Self::make_int_palette(&mut palette, result.transfer, posterize, result.grayscale);
+            palette_error = Some(match output {
+                RemapOutput::Rows(mut output_pixels) => remap_to_palette(image, &mut output_pixels, &mut palette, &result.cancel, result.threads())?.0,
+                RemapOutput::Sink(sink) => {
+                    if sink.palette(int_palette.as_slice()) == ControlFlow::Break {
+                        return Err(Error::Aborted { stage: Stage::Remapping });
+                    }
+                    remap_to_palette_sink(image, sink, &palette, &result.cancel, result.threads())?
+                },
+            });
         } else {
             let is_image_huge = (image.px.width * image.px.height) > 2000 * 2000;
             let allow_dither_map = result.use_dither_map == DitherMapMode::Always || (!is_image_huge && result.use_dither_map != DitherMapMode::None);
             let generate_dither_map = allow_dither_map && (image.edges.is_some() && image.dither_map.is_none());
-            if generate_dither_map {
+            let mut output_image_is_remapped = false;
+            // the sink can't be read back, so streamed output is dithered using the edges only
+            if let (true, RemapOutput::Rows(output_pixels)) = (generate_dither_map, &mut output) {
                 // If dithering (with dither map) is required, this image is used to find areas that require dithering
-                let (tmp_re, row_pointers_remapped) = remap_to_palette(image, &mut output_pixels, &mut palette)?;
+                let dither_map_start = Instant::now();
+                let (tmp_re, row_pointers_remapped) = remap_to_palette(image, output_pixels, &mut palette, &result.cancel, result.threads())?;
                 palette_error = Some(tmp_re);
                 image.update_dither_map(&row_pointers_remapped, &mut palette);
+                output_image_is_remapped = true;
+                let duration = dither_map_start.elapsed();
+                dither_map_duration = Some(duration);
+                result.emit(Event::DitherMap { duration });
             }
-            let output_image_is_remapped = generate_dither_map;
 
             if result.remap_progress(progress_stage1 as f32 * 0.5) {
-                return Err(LIQ_ABORTED);
+                return Err(Error::Aborted { stage: Stage::Remapping });
             }
 
             // remapping above was the last chance to do K-Means iteration, hence the final palette is set after remapping
-            int_palette = Self::make_int_palette(&mut palette, result.gamma, posterize);
+            int_palette = Self::make_int_palette(&mut palette, result.transfer, posterize, result.grayscale);
+            if let RemapOutput::Sink(sink) = &mut output {
+                if sink.palette(int_palette.as_slice()) == ControlFlow::Break {
+                    return Err(Error::Aborted { stage: Stage::Remapping });
+                }
+            }
             let max_dither_error = (palette_error.unwrap_or(quality_to_mse(80)) * 2.4).max(quality_to_mse(35)) as f32;
-            remap_to_palette_floyd(image, output_pixels, result, max_dither_error, output_image_is_remapped)?;
+            remap_to_palette_floyd(image, output, result, max_dither_error, output_image_is_remapped)?;
         }
+        let duration = start.elapsed();
+        result.emit(Event::Remap {
+            remapping_error: if result.dither_level == 0. { palette_error.map(mse_to_standard_mse) } else { None },
+            duration,
+        });
 
         Ok(Self {
-            int_palette, palette_error,
+            int_palette, palette_error, duration, dither_map_duration,
         })
     }
 
     /// Also rounds the input pal
-    pub fn make_int_palette(palette: &mut PalF, gamma: f64, posterize: u8) -> Palette {
+    pub fn make_int_palette(palette: &mut PalF, transfer: TransferFunction, posterize: u8, grayscale: bool) -> Palette {
         let mut int_palette = Palette {
             count: palette.len() as _,
             entries: [Default::default(); 256],
         };
-        let lut = gamma_lut(gamma);
+        let lut = gamma_lut(transfer);
+        // in grayscale mode, rounding must not add a tint to gray palettes
+        let is_gray = grayscale && palette.as_slice().iter().all(|c| c.is_neutral());
         for ((f_color, f_pop), int_pal) in palette.iter_mut().zip(int_palette.as_mut_slice()) {
-            let mut px = f_color.to_rgb(gamma)
+            let mut px = f_color.to_rgb(transfer)
                 .map(move |c| posterize_channel(c, posterize));
+            if is_gray {
+                px.r = px.g;
+                px.b = px.g;
+            }
             *f_color = f_pixel::from_rgba(&lut, px);
             if px.a == 0 && !f_pop.is_fixed() {
-                px.r = 71u8;
+                px.r = if is_gray { 112u8 } else { 71u8 };
                 px.g = 112u8;
-                px.b = 76u8;
+                px.b = if is_gray { 112u8 } else { 76u8 };
             }
             *int_pal = px;
         }
@@ -1,14 +1,29 @@
 use crate::error::*;
-use crate::pal::{f_pixel, gamma_lut, RGBA};
+use crate::pal::{f_pixel, gamma_lut, gamma_lut16, gray, gray16, ComponentOrder, GamutMatrix, LinearTransfer, TransferFunction, RGBA, RGBA16, RGBAF};
 use crate::seacow::{liq_ownership, SeaCow};
+use crate::yuv::YuvSource;
 use crate::LIQ_HIGH_MEMORY_LIMIT;
+use rgb::ComponentMap;
 use std::mem::MaybeUninit;
 
 pub(crate) type RowCallback = dyn Fn(&mut [MaybeUninit<RGBA>], usize) + Send + Sync;
 
 pub(crate) enum PixelsSource<'pixels, 'rows> {
-    Pixels { rows: SeaCow<'rows, *const RGBA>, pixels: Option<SeaCow<'pixels, RGBA>> },
+    /// `order` other than RGBA is swizzled on the fly, so the `RGBA` type of the rows is only a 4-byte storage
+    Pixels { rows: SeaCow<'rows, *const RGBA>, pixels: Option<SeaCow<'pixels, RGBA>>, order: ComponentOrder },
     Callback(Box<RowCallback>),
+    /// High bit depth pixels are converted to `f_pixel` directly, without going through 8-bit `RGBA`
+    Pixels16 { rows: SeaCow<'rows, *const RGBA16> },
+    /// Linear light, converted to `f_pixel` directly
+    PixelsF32 { rows: SeaCow<'rows, *const RGBAF>, transfer: LinearTransfer },
+    /// One byte per pixel, or two if followed by alpha
+    Gray { rows: SeaCow<'rows, *const u8>, has_alpha: bool },
+    /// Indices into a palette, which is padded to 256 entries
+    Indexed { rows: SeaCow<'rows, *const u8>, palette: Box<[RGBA; 256]> },
+    /// Video frames, converted to RGBA one row at a time
+    Yuv(Box<YuvSource<'pixels>>),
+    /// A rectangle of another image, reading from its cache if it has one
+    View { parent: &'pixels DynamicRows<'pixels, 'rows>, left: usize, top: usize },
 }
 
 pub(crate) struct DynamicRows<'pixels, 'rows> {
@@ -16,7 +31,15 @@ pub(crate) struct DynamicRows<'pixels, 'rows> {
     pub(crate) height: u32,
     f_pixels: Option<Box<[f_pixel]>>,
     pixels: PixelsSource<'pixels, 'rows>,
-    pub(crate) gamma: f64,
+    pub(crate) transfer: TransferFunction,
+    /// Only for high bit depth sources
+    gamma_lut16: Option<Box<[f32]>>,
+    /// Colors are reduced to luma as they're read
+    pub(crate) grayscale: bool,
+    /// Only for indexed sources, which are converted by lookup instead of being cached
+    palette_f: Option<Box<[f_pixel; 256]>>,
+    /// Conversion from the image's primaries to the output's
+    pub(crate) gamut: Option<GamutMatrix>,
 }
 
 pub(crate) struct DynamicRowsIter<'parent, 'pixels, 'rows> {
@@ -29,11 +52,9 @@ impl<'a, 'pixels, 'rows> DynamicRowsIter<'a, 'pixels, 'rows> {
         match self.px.f_pixels.as_ref() {
             Some(pixels) => &pixels[self.px.width as usize * row as usize..],
             None => {
-                let lut = gamma_lut(self.px.gamma);
-                let row_pixels = self.px.row_rgba(temp_row, row);
-
+                let lut = gamma_lut(self.px.transfer);
                 let t = self.temp_f_row.as_mut().unwrap();
-                DynamicRows::convert_row_to_f(t, row_pixels, &lut)
+                self.px.row_f_into(temp_row, t, row, &lut)
             },
         }
     }
@@ -42,10 +63,8 @@ impl<'a, 'pixels, 'rows> DynamicRowsIter<'a, 'pixels, 'rows> {
         match self.px.f_pixels.as_ref() {
             Some(pixels) => &pixels[self.px.width as usize * row as usize..],
             None => {
-                let lut = gamma_lut(self.px.gamma);
-                let row_pixels = self.px.row_rgba(temp_row, row);
-
-                DynamicRows::convert_row_to_f(temp_row_f, row_pixels, &lut)
+                let lut = gamma_lut(self.px.transfer);
+                self.px.row_f_into(temp_row, temp_row_f, row, &lut)
             },
         }
     }
@@ -53,26 +72,237 @@ impl<'a, 'pixels, 'rows> DynamicRowsIter<'a, 'pixels, 'rows> {
     pub fn row_rgba<'px>(&'px self, temp_row: &'px mut [MaybeUninit<RGBA>], row: usize) -> &'px [RGBA] {
         self.px.row_rgba(temp_row, row)
     }
+
+    /// `None` if the image isn't high bit depth
+    pub fn row_rgba16<'px>(&'px self, temp_row: &'px mut [MaybeUninit<RGBA16>], row: usize) -> Option<&'px [RGBA16]> {
+        self.px.row_rgba16(temp_row, row)
+    }
+
+    #[inline]
+    pub fn temp_row_width(&self) -> usize {
+        self.px.temp_row_width()
+    }
 }
 
 impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
     #[inline]
-    pub(crate) fn new(width: u32, height: u32, pixels: PixelsSource<'pixels, 'rows>, gamma: f64) -> Self {
-        debug_assert!(gamma > 0.);
-        Self { width, height, f_pixels: None, pixels, gamma }
+    pub(crate) fn new(width: u32, height: u32, pixels: PixelsSource<'pixels, 'rows>, transfer: TransferFunction, grayscale: bool) -> Self {
+        let gamma_lut16 = if let PixelsSource::Pixels16 { .. } = pixels { Some(gamma_lut16(transfer)) } else { None };
+        let mut rows = Self { width, height, f_pixels: None, pixels, transfer, gamma_lut16, grayscale, palette_f: None, gamut: None };
+        rows.palette_f = rows.make_palette_f();
+        rows
+    }
+
+    fn make_palette_f(&self) -> Option<Box<[f_pixel; 256]>> {
+        let palette = match &self.pixels {
+            PixelsSource::Indexed { palette, .. } => palette,
+            _ => return None,
+        };
+        let lut = gamma_lut(self.transfer);
+        let mut palette_f = Box::new([f_pixel::default(); 256]);
+        for (dst, &px) in palette_f.iter_mut().zip(palette.iter()) {
+            let px = f_pixel::from_rgba(&lut, if self.grayscale { gray(px) } else { px });
+            *dst = match &self.gamut {
+                Some(m) => px.convert_gamut(m),
+                None => px,
+            };
+        }
+        Some(palette_f)
+    }
+
+    /// Must be set before pixels are converted
+    pub(crate) fn set_gamut(&mut self, gamut: Option<GamutMatrix>) -> Result<(), Error> {
+        if self.f_pixels.is_some() {
+            return Err(Error::Unsupported("color space must be set before the image is quantized"));
+        }
+        self.gamut = gamut;
+        self.palette_f = self.make_palette_f();
+        Ok(())
+    }
+
+    fn row_rgba<'px>(&'px self, temp_row: &'px mut [MaybeUninit<RGBA>], row: usize) -> &'px [RGBA] {
+        if !self.grayscale || matches!(self.pixels, PixelsSource::Gray { .. } | PixelsSource::View { .. }) {
+            return self.row_rgba_color(temp_row, row);
+        }
+        if let PixelsSource::Pixels { rows, order: ComponentOrder::RGBA, .. } = &self.pixels {
+            let src = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], self.width()) };
+            for (dst, px) in temp_row.iter_mut().zip(src) {
+                dst.write(gray(*px));
+            }
+            // Safe, just initialized
+            return unsafe { slice_assume_init_mut(&mut temp_row[..src.len()]) };
+        }
+        // all other sources are converted into temp_row
+        let len = self.row_rgba_color(temp_row, row).len();
+        // Safe, initialized by row_rgba_color
+        let row_pixels = unsafe { slice_assume_init_mut(&mut temp_row[..len]) };
+        for px in row_pixels.iter_mut() {
+            *px = gray(*px);
+        }
+        row_pixels
     }
 
-    fn row_rgba<'px>(&'px self, temp_row: &'px mut [MaybeUninit<RGBA>], row: usize) -> &[RGBA] {
+    fn row_rgba_color<'px>(&'px self, temp_row: &'px mut [MaybeUninit<RGBA>], row: usize) -> &'px [RGBA] {
         match &self.pixels {
-            PixelsSource::Pixels { rows, .. } => unsafe {
+            PixelsSource::Pixels { rows, order: ComponentOrder::RGBA, .. } => unsafe {
                 std::slice::from_raw_parts(rows.as_slice()[row], self.width())
             },
+            PixelsSource::Pixels { rows, order, .. } => {
+                let order = *order;
+                let src = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], self.width()) };
+                for (dst, px) in temp_row.iter_mut().zip(src) {
+                    dst.write(order.unpack([px.r, px.g, px.b, px.a]));
+                }
+                // Safe, just initialized
+                unsafe { slice_assume_init_mut(&mut temp_row[..src.len()]) }
+            },
             PixelsSource::Callback(cb) => {
                 cb(temp_row, row);
                 // FIXME: cb needs to be marked as unsafe, since it's responsible for initialization :(
                 unsafe { slice_assume_init_mut(temp_row) }
+            },
+            PixelsSource::Pixels16 { rows } => {
+                let src = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], self.width()) };
+                for (dst, px) in temp_row.iter_mut().zip(src) {
+                    dst.write(px.map(|c| ((c as u32 * 255 + 32767) / 65535) as u8));
+                }
+                // Safe, just initialized
+                unsafe { slice_assume_init_mut(&mut temp_row[..src.len()]) }
+            },
+            PixelsSource::PixelsF32 { rows, transfer } => {
+                let src = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], self.width()) };
+                for (dst, px) in temp_row.iter_mut().zip(src) {
+                    let c = |c| (transfer.to_output(c) * 255. + 0.5) as u8;
+                    dst.write(RGBA::new(c(px.r), c(px.g), c(px.b), (px.a.clamp(0., 1.) * 255. + 0.5) as u8));
+                }
+                // Safe, just initialized
+                unsafe { slice_assume_init_mut(&mut temp_row[..src.len()]) }
+            },
+            PixelsSource::Gray { rows, has_alpha } => {
+                let channels = if *has_alpha { 2 } else { 1 };
+                let src = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], self.width() * channels) };
+                for (dst, px) in temp_row.iter_mut().zip(src.chunks_exact(channels)) {
+                    let y = px[0];
+                    dst.write(RGBA::new(y, y, y, if *has_alpha { px[1] } else { 255 }));
+                }
+                // Safe, just initialized
+                unsafe { slice_assume_init_mut(&mut temp_row[..self.width()]) }
+            },
+            // temp_row is as wide as the parent's rows
+            PixelsSource::View { parent, left, top } => &parent.row_rgba(temp_row, top + row)[*left..][..self.width()],
+            PixelsSource::Yuv(yuv) => {
+                let temp_row = &mut temp_row[..self.width()];
+                yuv.convert_row(temp_row, row);
+                // Safe, just initialized
+                unsafe { slice_assume_init_mut(temp_row) }
+            },
+            PixelsSource::Indexed { rows, palette } => {
+                let src = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], self.width()) };
+                for (dst, &idx) in temp_row.iter_mut().zip(src) {
+                    dst.write(palette[idx as usize]);
+                }
+                // Safe, just initialized
+                unsafe { slice_assume_init_mut(&mut temp_row[..src.len()]) }
+            },
+        }
+    }
+
+    /// Palette and indices of a row, if the image is indexed
+    pub(crate) fn row_indexed(&self, row: usize) -> Option<(&[RGBA; 256], &[u8])> {
+        match &self.pixels {
+            PixelsSource::Indexed { rows, palette } => Some((palette, unsafe {
+                std::slice::from_raw_parts(rows.as_slice()[row], self.width())
+            })),
+            _ => None,
+        }
+    }
+
+    /// High bit depth sources give more than 8 bits of precision to the histogram
+    fn row_rgba16<'px>(&'px self, temp_row: &'px mut [MaybeUninit<RGBA16>], row: usize) -> Option<&'px [RGBA16]> {
+        match &self.pixels {
+            PixelsSource::Pixels16 { rows } if self.grayscale => {
+                let src = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], self.width()) };
+                for (dst, px) in temp_row.iter_mut().zip(src) {
+                    dst.write(gray16(*px));
+                }
+                // Safe, just initialized
+                Some(unsafe { slice_assume_init_mut(&mut temp_row[..src.len()]) })
+            },
+            PixelsSource::Pixels16 { rows } => Some(unsafe {
+                std::slice::from_raw_parts(rows.as_slice()[row], self.width())
+            }),
+            PixelsSource::PixelsF32 { rows, transfer } => {
+                let src = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], self.width()) };
+                for (dst, px) in temp_row.iter_mut().zip(src) {
+                    let c = |c| (transfer.to_output(c) * 65535. + 0.5) as u16;
+                    let px = RGBA16::new(c(px.r), c(px.g), c(px.b), (px.a.clamp(0., 1.) * 65535. + 0.5) as u16);
+                    dst.write(if self.grayscale { gray16(px) } else { px });
+                }
+                // Safe, just initialized
+                Some(unsafe { slice_assume_init_mut(&mut temp_row[..src.len()]) })
+            },
+            PixelsSource::View { parent, left, top } => Some(&parent.row_rgba16(temp_row, top + row)?[*left..][..self.width()]),
+            _ => None,
+        }
+    }
+
+    /// Converts to `f_pixel` from the most precise representation of the row available
+    fn row_f_into<'f>(&self, temp_row: &mut [MaybeUninit<RGBA>], row_f_pixels: &'f mut [MaybeUninit<f_pixel>], row: usize, gamma_lut: &[f32; 256]) -> &'f mut [f_pixel] {
+        let row_f_pixels = self.row_f_into_source(temp_row, row_f_pixels, row, gamma_lut);
+        // indexed palette and parent image have been converted already
+        if let (Some(m), false) = (&self.gamut, self.is_indexed() || matches!(self.pixels, PixelsSource::View { .. })) {
+            for px in row_f_pixels.iter_mut() {
+                *px = px.convert_gamut(m);
             }
         }
+        row_f_pixels
+    }
+
+    fn row_f_into_source<'f>(&self, temp_row: &mut [MaybeUninit<RGBA>], row_f_pixels: &'f mut [MaybeUninit<f_pixel>], row: usize, gamma_lut: &[f32; 256]) -> &'f mut [f_pixel] {
+        if let PixelsSource::View { parent, left, top } = &self.pixels {
+            let width = self.width();
+            if let Some(f_pixels) = parent.f_pixels.as_deref() {
+                let row_f_pixels = &mut row_f_pixels[..width];
+                for (dst, px) in row_f_pixels.iter_mut().zip(&f_pixels[(top + row) * parent.width() + left..]) {
+                    dst.write(*px);
+                }
+                // Safe, just initialized
+                return unsafe { slice_assume_init_mut(row_f_pixels) };
+            }
+            // temp rows are as wide as the parent's rows, so the parent's row is converted in place, and then moved to the start
+            let parent_row = parent.row_f_into(temp_row, row_f_pixels, top + row, gamma_lut);
+            parent_row.copy_within(*left..left + width, 0);
+            return &mut parent_row[..width];
+        }
+        let row_f_pixels = &mut row_f_pixels[..self.width()];
+        if let (PixelsSource::Indexed { rows, .. }, Some(palette_f)) = (&self.pixels, self.palette_f.as_deref()) {
+            let src = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], self.width()) };
+            for (dst, &idx) in row_f_pixels.iter_mut().zip(src) {
+                dst.write(palette_f[idx as usize]);
+            }
+            // Safe, just initialized
+            return unsafe { slice_assume_init_mut(row_f_pixels) };
+        }
+        match (&self.pixels, self.gamma_lut16.as_deref()) {
+            (PixelsSource::Pixels16 { rows }, Some(lut16)) => {
+                let src = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], self.width()) };
+                for (dst, px) in row_f_pixels.iter_mut().zip(src) {
+                    dst.write(f_pixel::from_rgba16(lut16, if self.grayscale { gray16(*px) } else { *px }));
+                }
+            },
+            (PixelsSource::PixelsF32 { rows, transfer }, _) => {
+                let src = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], self.width()) };
+                for (dst, px) in row_f_pixels.iter_mut().zip(src) {
+                    dst.write(if self.grayscale { f_pixel::from_linear_gray(transfer, *px) } else { f_pixel::from_linear(transfer, *px) });
+                }
+            },
+            _ => {
+                let row_pixels = self.row_rgba(temp_row, row);
+                return Self::convert_row_to_f(row_f_pixels, row_pixels, gamma_lut);
+            },
+        }
+        // Safe, just initialized
+        unsafe { slice_assume_init_mut(row_f_pixels) }
     }
 
     fn convert_row_to_f<'f>(row_f_pixels: &'f mut [MaybeUninit<f_pixel>], row_pixels: &[RGBA], gamma_lut: &[f32; 256]) -> &'f mut [f_pixel] {
@@ -90,8 +320,8 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
     }
 
     #[inline]
-    fn prepare_f_pixels(&mut self, temp_row: &mut [MaybeUninit<RGBA>], allow_steamed: bool) -> Result<Option<Box<[MaybeUninit<f_pixel>]>>, liq_error> {
-        debug_assert_eq!(temp_row.len(), self.width as _);
+    fn prepare_f_pixels(&mut self, temp_row: &mut [MaybeUninit<RGBA>], allow_steamed: bool) -> Result<Option<Box<[MaybeUninit<f_pixel>]>>, Error> {
+        debug_assert_eq!(temp_row.len(), self.temp_row_width());
         if self.f_pixels.is_some() {
             return Ok(None);
         }
@@ -99,20 +329,28 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
         self.prepare_generated_image(temp_row, allow_steamed)
     }
 
-    fn prepare_generated_image(&mut self, temp_row: &mut [MaybeUninit<RGBA>], allow_steamed: bool) -> Result<Option<Box<[MaybeUninit<f_pixel>]>>, liq_error> {
-        debug_assert_eq!(temp_row.len(), self.width as _);
+    fn prepare_generated_image(&mut self, temp_row: &mut [MaybeUninit<RGBA>], allow_steamed: bool) -> Result<Option<Box<[MaybeUninit<f_pixel>]>>, Error> {
+        debug_assert_eq!(temp_row.len(), self.temp_row_width());
 
-        if allow_steamed && self.should_use_low_memory() {
-            return Ok(Some(temp_buf(self.width())));
+        // palette lookup or parent's cache are as fast as reading a cached copy
+        if allow_steamed && (self.should_use_low_memory() || self.is_indexed() || self.is_view_of_cached()) {
+            return Ok(Some(temp_buf(self.temp_row_width())));
         }
 
 
         let width = self.width();
-        let lut = gamma_lut(self.gamma);
+        let lut = gamma_lut(self.transfer);
         let mut f_pixels = temp_buf(self.width() * self.height());
+        // rows of views are converted at the parent's width
+        let mut wide_row_f = temp_buf(if self.temp_row_width() > width { self.temp_row_width() } else { 0 });
         for (row, f_row) in f_pixels.chunks_exact_mut(width).enumerate() {
-            let row_pixels = self.row_rgba(temp_row, row);
-            Self::convert_row_to_f(f_row, row_pixels, &lut);
+            if wide_row_f.is_empty() {
+                self.row_f_into(temp_row, f_row, row, &lut);
+            } else {
+                for (dst, px) in f_row.iter_mut().zip(self.row_f_into(temp_row, &mut wide_row_f, row, &lut)) {
+                    dst.write(*px);
+                }
+            }
         }
         // just initialized
         self.f_pixels = Some(unsafe { box_assume_init(f_pixels) });
@@ -120,7 +358,7 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
     }
 
     #[inline]
-    pub fn rows_iter(&mut self, temp_row: &mut [MaybeUninit<RGBA>]) -> Result<DynamicRowsIter<'_, 'pixels, 'rows>, liq_error> {
+    pub fn rows_iter(&mut self, temp_row: &mut [MaybeUninit<RGBA>]) -> Result<DynamicRowsIter<'_, 'pixels, 'rows>, Error> {
         Ok(DynamicRowsIter {
             temp_f_row: self.prepare_f_pixels(temp_row, true)?,
             px: self,
@@ -128,37 +366,86 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
     }
 
     #[inline]
-    pub fn rgba_rows_iter(&self) -> Result<DynamicRowsIter<'_, 'pixels, 'rows>, liq_error> {
-        // This happens when histogram image is recycled
-        if let PixelsSource::Pixels { rows, .. } = &self.pixels {
-            if rows.as_slice().is_empty() {
-                return Err(LIQ_UNSUPPORTED);
-            }
+    pub fn rgba_rows_iter(&self) -> Result<DynamicRowsIter<'_, 'pixels, 'rows>, Error> {
+        if !self.has_rgba_source() {
+            return Err(Error::Unsupported("pixels are no longer available"));
         }
         Ok(DynamicRowsIter { px: self, temp_f_row: None })
     }
 
+    fn has_rgba_source(&self) -> bool {
+        match &self.pixels {
+            // This happens when histogram image is recycled
+            PixelsSource::Pixels { rows, .. } => !rows.as_slice().is_empty(),
+            PixelsSource::View { parent, .. } => parent.has_rgba_source(),
+            _ => true,
+        }
+    }
+
+    /// Rectangle of this image, sharing its source and cached pixels
+    pub(crate) fn view(&self, left: usize, top: usize, width: u32, height: u32) -> DynamicRows<'_, 'rows> {
+        DynamicRows {
+            width,
+            height,
+            f_pixels: None,
+            pixels: PixelsSource::View { parent: self, left, top },
+            transfer: self.transfer,
+            gamma_lut16: None,
+            grayscale: self.grayscale,
+            palette_f: None,
+            gamut: self.gamut,
+        }
+    }
+
+    /// Views read rows of their parent, so temporary rows given to them must be as wide as the outermost image
+    #[inline]
+    pub(crate) fn temp_row_width(&self) -> usize {
+        match &self.pixels {
+            PixelsSource::View { parent, .. } => parent.temp_row_width(),
+            _ => self.width(),
+        }
+    }
+
+    fn is_view_of_cached(&self) -> bool {
+        matches!(&self.pixels, PixelsSource::View { parent, .. } if parent.f_pixels.is_some())
+    }
+
+    /// Uses cached pixels if available, otherwise converts the row into `temp_row_f`
+    pub(crate) fn row_f_shared<'px>(&'px self, temp_row: &mut [MaybeUninit<RGBA>], temp_row_f: &'px mut [MaybeUninit<f_pixel>], row: usize, gamma_lut: &[f32; 256]) -> &'px [f_pixel] {
+        match self.f_pixels.as_ref() {
+            Some(pixels) => &pixels[self.width() * row..][..self.width()],
+            None => self.row_f_into(temp_row, temp_row_f, row, gamma_lut),
+        }
+    }
+
+    /// Indexed images are never cached, since palette lookup is cheap
+    #[inline]
+    pub(crate) fn is_indexed(&self) -> bool {
+        self.palette_f.is_some()
+    }
+
     #[inline]
-    pub fn all_rows_f(&mut self) -> Result<&[f_pixel], liq_error> {
+    pub fn all_rows_f(&mut self) -> Result<&[f_pixel], Error> {
         if self.f_pixels.is_some() {
             return Ok(self.f_pixels.as_ref().unwrap()); // borrow-checker :(
         }
-        let _ = self.prepare_f_pixels(&mut temp_buf(self.width()), false)?;
-        self.f_pixels.as_deref().ok_or(LIQ_UNSUPPORTED)
+        let _ = self.prepare_f_pixels(&mut temp_buf(self.temp_row_width()), false)?;
+        self.f_pixels.as_deref().ok_or(Error::Unsupported("image is too large to convert at once"))
     }
 
     /// Not recommended
-    pub(crate) unsafe fn set_memory_ownership(&mut self, ownership_flags: liq_ownership) -> Result<(), liq_error> {
+    pub(crate) unsafe fn set_memory_ownership(&mut self, ownership_flags: liq_ownership) -> Result<(), Error> {
         let both = liq_ownership::LIQ_OWN_ROWS | liq_ownership::LIQ_OWN_PIXELS;
+        let out_of_range = Error::out_of_range("ownership flags", ownership_flags.bits());
 
         if ownership_flags.is_empty() || (ownership_flags | both) != both {
-            return Err(LIQ_VALUE_OUT_OF_RANGE);
+            return Err(out_of_range);
         }
 
         if ownership_flags.contains(liq_ownership::LIQ_OWN_ROWS) {
             match &mut self.pixels {
                 PixelsSource::Pixels { rows, .. } => rows.make_owned(),
-                PixelsSource::Callback(_) => return Err(LIQ_VALUE_OUT_OF_RANGE),
+                PixelsSource::Callback(_) | PixelsSource::Pixels16 { .. } | PixelsSource::PixelsF32 { .. } | PixelsSource::Gray { .. } | PixelsSource::Indexed { .. } | PixelsSource::Yuv(_) | PixelsSource::View { .. } => return Err(out_of_range),
             }
         }
 
@@ -166,20 +453,31 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
             let len = self.width() * self.height();
             match &mut self.pixels {
                 PixelsSource::Pixels { pixels: Some(pixels), .. } => pixels.make_owned(),
-                PixelsSource::Pixels { pixels, rows } => {
+                PixelsSource::Pixels { pixels, rows, .. } => {
                     // the row with the lowest address is assumed to be at the start of the bitmap
-                    let ptr = rows.as_slice().iter().copied().min().ok_or(LIQ_UNSUPPORTED)?;
+                    let ptr = rows.as_slice().iter().copied().min().ok_or(Error::Unsupported("image has no rows"))?;
                     *pixels = Some(SeaCow::c_owned(ptr as *mut _, len));
                 },
-                PixelsSource::Callback(_) => return Err(LIQ_VALUE_OUT_OF_RANGE),
+                PixelsSource::Callback(_) | PixelsSource::Pixels16 { .. } | PixelsSource::PixelsF32 { .. } | PixelsSource::Gray { .. } | PixelsSource::Indexed { .. } | PixelsSource::Yuv(_) | PixelsSource::View { .. } => return Err(out_of_range),
             }
         }
         Ok(())
     }
 
+    /// Has more than 8 bits per channel
+    #[inline]
+    pub fn is_high_precision(&self) -> bool {
+        match &self.pixels {
+            PixelsSource::Pixels16 { .. } | PixelsSource::PixelsF32 { .. } => true,
+            PixelsSource::View { parent, .. } => parent.is_high_precision(),
+            _ => false,
+        }
+    }
+
     pub fn free_histogram_inputs(&mut self) {
         if self.f_pixels.is_some() {
-            self.pixels = PixelsSource::Pixels { rows: SeaCow::borrowed(&[]), pixels: None };
+            self.pixels = PixelsSource::Pixels { rows: SeaCow::borrowed(&[]), pixels: None, order: ComponentOrder::RGBA };
+            self.gamma_lut16 = None;
         }
     }
 
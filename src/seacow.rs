@@ -1,5 +1,6 @@
 use std::mem::MaybeUninit;
 use std::os::raw::c_int;
+use crate::pal::{RGBA, RGBA16};
 
 pub struct SeaCow<'a, T> {
     inner: SeaCowInner<'a, T>,
@@ -11,6 +12,14 @@ unsafe impl<T: Sync> Sync for SeaCowInner<'_, T> {}
 unsafe impl<T: Send> Send for SeaCow<'_, *const T> {}
 unsafe impl<T: Sync> Sync for SeaCow<'_, *const T> {}
 
+// Implementing an auto trait for `*const T` above opts every other `SeaCow<T>` out of it
+unsafe impl Send for SeaCow<'_, RGBA> {}
+unsafe impl Sync for SeaCow<'_, RGBA> {}
+unsafe impl Send for SeaCow<'_, RGBA16> {}
+unsafe impl Sync for SeaCow<'_, RGBA16> {}
+unsafe impl Send for SeaCow<'_, u8> {}
+unsafe impl Sync for SeaCow<'_, u8> {}
+
 impl<'a, T> SeaCow<'a, T> {
     #[inline]
     pub fn borrowed(data: &'a [T]) -> Self {
@@ -133,6 +142,19 @@ impl<'a, T: Sync + Send + Copy + 'static> RowBitmapMut<'a, T> {
         }
     }
 
+    /// Rows start every `stride` elements. The last row doesn't need padding.
+    #[cfg(feature = "imgref")]
+    #[inline]
+    pub fn new_strided(data: &mut [T], width: usize, height: usize, stride: usize) -> Option<Self> {
+        if stride < width || height == 0 || data.len() < stride * (height - 1) + width {
+            return None;
+        }
+        Some(Self {
+            rows: MutCow::Owned(data.chunks_mut(stride).take(height).map(|r| r.as_mut_ptr()).collect()),
+            width,
+        })
+    }
+
     /// Innter pointers must be valid for `'a` too, and at least `width` large each
     #[inline]
     pub unsafe fn new(rows: &'a mut [*mut T], width: usize) -> Self {
@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+/// Timings and intermediate results of a quantization. See [`QuantizationResult::stats`](crate::QuantizationResult::stats).
+///
+/// Errors are in the same units as [`QuantizationResult::quantization_error`](crate::QuantizationResult::quantization_error).
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct QuantizationStats {
+    /// Time spent adding images or colors, and preparing the histogram
+    pub histogram_time: Duration,
+    /// Unique colors before the histogram was posterized to fit `max_histogram_entries`
+    pub histogram_entries_before_posterization: usize,
+    /// Unique colors the palette was searched for, excluding fixed colors
+    pub histogram_entries: usize,
+    /// Bits dropped from each channel to make the histogram smaller
+    pub posterize_bits: u8,
+    /// All median cuts, including ones done in `trials`
+    pub median_cut_time: Duration,
+    /// Feedback loop trials, in order
+    pub trials: Vec<TrialStats>,
+    /// Error of the best palette found in the trials
+    pub trials_palette_error: Option<f64>,
+    /// K-means refinement of the best palette
+    pub kmeans_time: Duration,
+    pub kmeans_iterations: u16,
+    /// Error of the final palette. `None` if it wasn't measured.
+    pub palette_error: Option<f64>,
+    /// `None` until an image has been remapped
+    pub remap_time: Option<Duration>,
+    /// Part of `remap_time`. `None` if no dither map was made.
+    pub dither_map_time: Option<Duration>,
+    pub remapping_error: Option<f64>,
+}
+
+/// One round of the search for the best palette
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct TrialStats {
+    pub median_cut_time: Duration,
+    pub kmeans_time: Duration,
+    pub colors: usize,
+    pub palette_error: f64,
+}
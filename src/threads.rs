@@ -0,0 +1,98 @@
+//! Where parallel work runs. Without the `threads` feature, everything runs on the caller's thread.
+
+#[cfg(feature = "threads")]
+use rayon::iter::{ParallelBridge, ParallelIterator};
+#[cfg(feature = "threads")]
+use rayon::slice::ParallelSliceMut;
+#[cfg(feature = "threads")]
+use std::sync::Arc;
+
+/// The global rayon pool, a dedicated one, or only the caller's thread
+#[derive(Clone, Default)]
+pub(crate) enum Threads {
+    #[default]
+    Global,
+    #[cfg(feature = "threads")]
+    Pool(Arc<rayon::ThreadPool>),
+    Caller,
+}
+
+#[cfg(feature = "threads")]
+impl Threads {
+    #[inline]
+    pub fn current_num_threads(&self) -> usize {
+        match self {
+            Self::Global => rayon::current_num_threads(),
+            Self::Pool(pool) => pool.current_num_threads(),
+            Self::Caller => 1,
+        }
+    }
+
+    /// Calls `f` with chunks of `items`, in parallel
+    pub fn for_each_chunk<T: Send>(&self, items: &mut [T], chunk_size: usize, f: impl Fn(&mut [T]) + Send + Sync) {
+        match self {
+            Self::Global => items.par_chunks_mut(chunk_size).for_each(f),
+            Self::Pool(pool) => pool.install(|| items.par_chunks_mut(chunk_size).for_each(f)),
+            Self::Caller => items.chunks_mut(chunk_size).for_each(f),
+        }
+    }
+
+    /// Sum of `f` of all items, in parallel and in any order
+    pub fn sum<I>(&self, iter: I, f: impl Fn(I::Item) -> f64 + Send + Sync) -> f64 where I: Iterator + Send, I::Item: Send {
+        match self {
+            Self::Global => iter.par_bridge().map(f).sum(),
+            Self::Pool(pool) => pool.install(|| iter.par_bridge().map(f).sum()),
+            Self::Caller => iter.map(f).sum(),
+        }
+    }
+}
+
+#[cfg(not(feature = "threads"))]
+impl Threads {
+    #[inline(always)]
+    pub fn current_num_threads(&self) -> usize {
+        1
+    }
+
+    #[inline(always)]
+    pub fn for_each_chunk<T>(&self, items: &mut [T], chunk_size: usize, f: impl Fn(&mut [T])) {
+        items.chunks_mut(chunk_size).for_each(f);
+    }
+
+    #[inline(always)]
+    pub fn sum<I: Iterator>(&self, iter: I, f: impl Fn(I::Item) -> f64) -> f64 {
+        iter.map(f).sum()
+    }
+}
+
+#[cfg(not(feature = "threads"))]
+pub(crate) use self::rayoff::*;
+
+#[cfg(not(feature = "threads"))]
+mod rayoff {
+    use std::cell::OnceCell;
+
+    pub(crate) struct ThreadLocal<T>(OnceCell<T>);
+
+    impl<T> ThreadLocal<T> {
+        #[inline(always)]
+        pub fn new() -> Self {
+            Self(OnceCell::new())
+        }
+
+        #[inline(always)]
+        pub fn get_or(&self, f: impl FnOnce() -> T) -> &T {
+            self.0.get_or_init(f)
+        }
+    }
+
+    impl<T> IntoIterator for ThreadLocal<T> {
+        type IntoIter = std::option::IntoIter<T>;
+        type Item = T;
+
+        #[inline(always)]
+        fn into_iter(self) -> Self::IntoIter {
+            self.0.into_inner().into_iter()
+        }
+    }
+}
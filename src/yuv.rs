@@ -0,0 +1,147 @@
+use crate::error::*;
+use crate::pal::RGBA;
+use std::mem::MaybeUninit;
+
+/// How much smaller the chroma planes are than the luma plane
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// Half width and half height, e.g. I420 and NV12
+    Yuv420,
+    /// Half width, full height
+    Yuv422,
+    /// Full resolution
+    Yuv444,
+}
+
+/// Coefficients for converting YCbCr to RGB
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum YuvMatrix {
+    /// SD video and JPEG
+    Bt601,
+    /// HD video
+    Bt709,
+}
+
+/// Range of values used by the Y, Cb and Cr channels
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum YuvRange {
+    /// 0-255, e.g. JPEG
+    Full,
+    /// 16-235 for luma and 16-240 for chroma, which is usual for video
+    Limited,
+}
+
+/// Layout and color encoding of YUV frames
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct YuvFormat {
+    pub subsampling: ChromaSubsampling,
+    pub matrix: YuvMatrix,
+    pub range: YuvRange,
+}
+
+impl Default for YuvFormat {
+    /// 4:2:0 BT.601 limited range, the most common format of video frames
+    fn default() -> Self {
+        Self {
+            subsampling: ChromaSubsampling::Yuv420,
+            matrix: YuvMatrix::Bt601,
+            range: YuvRange::Limited,
+        }
+    }
+}
+
+/// Borrowed luma and chroma planes of a frame. Strides are in bytes.
+#[derive(Debug, Copy, Clone)]
+pub struct YuvPlanes<'pixels> {
+    y: &'pixels [u8],
+    y_stride: usize,
+    u: &'pixels [u8],
+    v: &'pixels [u8],
+    uv_stride: usize,
+    /// 1 for planar, 2 for interleaved chroma
+    uv_step: usize,
+}
+
+impl<'pixels> YuvPlanes<'pixels> {
+    /// Separate Y, U (Cb) and V (Cr) planes, e.g. I420
+    #[must_use]
+    pub fn planar(y: &'pixels [u8], y_stride: usize, u: &'pixels [u8], v: &'pixels [u8], uv_stride: usize) -> Self {
+        Self { y, y_stride, u, v, uv_stride, uv_step: 1 }
+    }
+
+    /// Y plane followed by a plane of interleaved U and V, e.g. NV12
+    #[must_use]
+    pub fn semi_planar(y: &'pixels [u8], y_stride: usize, uv: &'pixels [u8], uv_stride: usize) -> Self {
+        Self { y, y_stride, u: uv, v: uv.get(1..).unwrap_or_default(), uv_stride, uv_step: 2 }
+    }
+
+    /// Y plane followed by a plane of interleaved V and U, e.g. NV21
+    #[must_use]
+    pub fn semi_planar_vu(y: &'pixels [u8], y_stride: usize, vu: &'pixels [u8], uv_stride: usize) -> Self {
+        Self { y, y_stride, u: vu.get(1..).unwrap_or_default(), v: vu, uv_stride, uv_step: 2 }
+    }
+}
+
+/// Planes checked to be large enough for the image, with precomputed conversion
+pub(crate) struct YuvSource<'pixels> {
+    planes: YuvPlanes<'pixels>,
+    shift_x: u8,
+    shift_y: u8,
+    /// Y offset, Y scale, chroma scale
+    range: (f32, f32, f32),
+    /// Cr to R, Cb to G, Cr to G, Cb to B
+    coefs: [f32; 4],
+}
+
+impl<'pixels> YuvSource<'pixels> {
+    pub(crate) fn new(planes: YuvPlanes<'pixels>, width: usize, height: usize, format: YuvFormat) -> Result<Self, Error> {
+        let (shift_x, shift_y) = match format.subsampling {
+            ChromaSubsampling::Yuv420 => (1, 1),
+            ChromaSubsampling::Yuv422 => (1, 0),
+            ChromaSubsampling::Yuv444 => (0, 0),
+        };
+        let chroma_width = (width + (1 << shift_x) - 1) >> shift_x;
+        let chroma_height = (height + (1 << shift_y) - 1) >> shift_y;
+        if width == 0 || height == 0 {
+            return Err(Error::out_of_range("image size", (width * height) as f64));
+        }
+        if planes.y_stride < width {
+            return Err(Error::out_of_range("y_stride", planes.y_stride as f64));
+        }
+        if planes.uv_stride < chroma_width * planes.uv_step {
+            return Err(Error::out_of_range("uv_stride", planes.uv_stride as f64));
+        }
+        Error::check_len(planes.y_stride * (height - 1) + width, planes.y.len())?;
+        let chroma_len = planes.uv_stride * (chroma_height - 1) + (chroma_width - 1) * planes.uv_step + 1;
+        Error::check_len(chroma_len, planes.u.len())?;
+        Error::check_len(chroma_len, planes.v.len())?;
+
+        let range = match format.range {
+            YuvRange::Full => (0., 1., 1.),
+            YuvRange::Limited => (16., 255. / 219., 255. / 224.),
+        };
+        let coefs = match format.matrix {
+            YuvMatrix::Bt601 => [1.402, 0.344_136, 0.714_136, 1.772],
+            YuvMatrix::Bt709 => [1.5748, 0.187_324, 0.468_124, 1.8556],
+        };
+        Ok(Self { planes, shift_x, shift_y, range, coefs })
+    }
+
+    pub(crate) fn convert_row(&self, temp_row: &mut [MaybeUninit<RGBA>], row: usize) {
+        let p = &self.planes;
+        let y_row = &p.y[row * p.y_stride..][..temp_row.len()];
+        let uv_start = (row >> self.shift_y) * p.uv_stride;
+        let (u_row, v_row) = (&p.u[uv_start..], &p.v[uv_start..]);
+        let (y_offset, y_scale, uv_scale) = self.range;
+        let [cr_r, cb_g, cr_g, cb_b] = self.coefs;
+
+        for (col, (dst, &y)) in temp_row.iter_mut().zip(y_row).enumerate() {
+            let uv = (col >> self.shift_x) * p.uv_step;
+            let y = (y as f32 - y_offset) * y_scale;
+            let cb = (u_row[uv] as f32 - 128.) * uv_scale;
+            let cr = (v_row[uv] as f32 - 128.) * uv_scale;
+            let c = |c: f32| (c + 0.5).clamp(0., 255.) as u8;
+            dst.write(RGBA::new(c(y + cr_r * cr), c(y - cb_g * cb - cr_g * cr), c(y + cb_b * cb), 255));
+        }
+    }
+}